@@ -0,0 +1,417 @@
+//! Export all records of an activity at once, without real time playback
+use chrono::DateTime;
+use crate::{Cli, Units};
+use tpvfitplay::{
+    height, laps, samples, sport, start_offset, Clock, Fields, FitDataMap, Focus, Lap, Sample, Schema,
+};
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Output path '-' means stdout
+pub fn is_stdout(filename: &Path) -> bool {
+    filename == Path::new("-")
+}
+
+/// Buffered writer for the export file or stdout
+pub fn create(filename: &Path) -> io::Result<Box<dyn Write>> {
+    if is_stdout(filename) {
+        return Ok(Box::new(BufWriter::new(io::stdout())));
+    }
+    Ok(Box::new(BufWriter::new(File::create(filename)?)))
+}
+
+/// Drop the GPS positions of all messages for '--anonymize', the start, end and bounding box
+/// positions of laps and sessions too. Developer fields stay, the exports only write the ones
+/// '--map' or '--draft-field' route to a ride metric.
+pub fn strip_positions(data: &mut [FitDataMap]) {
+    for fdm in data.iter_mut() {
+        fdm.fields.retain(|name, _| !name.ends_with("_lat") && !name.ends_with("_long"));
+    }
+}
+
+/// Focus without the rider identity for '--anonymize'
+pub fn anonymize(focus: Focus) -> Focus {
+    let blank = Focus::new();
+    Focus {
+        name: blank.name,
+        country: blank.country,
+        team: blank.team,
+        teamCode: blank.teamCode,
+        ..focus
+    }
+}
+
+/// Write one focus object per line in the schema, only the '--fields' if given
+pub fn write_ndjson(
+    out: &mut dyn Write,
+    frames: impl Iterator<Item = Focus>,
+    fields: Option<&Fields>,
+    schema: Schema,
+) -> Result<(), Box<dyn Error>> {
+    for focus in frames {
+        write_focus(&mut *out, &focus, fields, schema)?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn write_focus(
+    out: &mut dyn Write,
+    focus: &Focus,
+    fields: Option<&Fields>,
+    schema: Schema,
+) -> serde_json::Result<()> {
+    let frame = schema.frame(focus);
+    match fields {
+        Some(fields) => serde_json::to_writer(out, &fields.select(&frame)),
+        None => serde_json::to_writer(out, &frame),
+    }
+}
+
+/// Streams all focus frames into a single JSON array, frame by frame
+pub struct ArrayExport {
+    out: Box<dyn Write>,
+    empty: bool,
+}
+
+impl ArrayExport {
+    /// Create the export and open the array
+    pub fn new(mut out: Box<dyn Write>) -> io::Result<ArrayExport> {
+        write!(out, "[")?;
+        Ok(ArrayExport { out, empty: true })
+    }
+
+    /// Append the frames of one activity
+    pub fn write(
+        &mut self,
+        frames: impl Iterator<Item = Focus>,
+        fields: Option<&Fields>,
+        schema: Schema,
+    ) -> Result<(), Box<dyn Error>> {
+        for focus in frames {
+            if !self.empty {
+                write!(self.out, ",")?;
+            }
+            write_focus(&mut self.out, &focus, fields, schema)?;
+            self.empty = false;
+        }
+        Ok(())
+    }
+
+    /// Close the array once all activities are written
+    pub fn finish(&mut self) -> io::Result<()> {
+        writeln!(self.out, "]")?;
+        self.out.flush()
+    }
+}
+
+/// Writes the GPS positions of all records as GPX track, one track per activity
+pub struct GpxExport {
+    out: Box<dyn Write>,
+}
+
+impl GpxExport {
+    /// Create the export and write the GPX header
+    pub fn new(mut out: Box<dyn Write>) -> io::Result<GpxExport> {
+        writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            out,
+            r#"<gpx version="1.1" creator="tpvfitplay" xmlns="http://www.topografix.com/GPX/1/1">"#
+        )?;
+        Ok(GpxExport { out })
+    }
+
+    /// Append one activity as track, records without a GPS position are left out
+    pub fn write(&mut self, data: &[FitDataMap], opt: &Cli) -> Result<(), Box<dyn Error>> {
+        let mut clock = Clock::new(data, &opt.convert);
+        let start = start_offset(data, &opt.convert);
+
+        writeln!(self.out, "  <trk>")?;
+        writeln!(self.out, "    <trkseg>")?;
+        for sample in samples(data, &opt.convert) {
+            let time = clock.tick(sample.timestamp).0 as u32;
+            if opt.convert.end.is_some_and(|end| time > end) {
+                break;
+            }
+            let (lat, long) = match sample.position {
+                Some(position) if time >= start => position,
+                _ => continue,
+            };
+
+            writeln!(self.out, r#"      <trkpt lat="{:.7}" lon="{:.7}">"#, lat, long)?;
+            if let Some(altitude) = sample.altitude {
+                writeln!(self.out, "        <ele>{:.1}</ele>", altitude)?;
+            }
+            if let Some(timestamp) = sample.timestamp.and_then(iso_time) {
+                writeln!(self.out, "        <time>{}</time>", timestamp)?;
+            }
+            writeln!(self.out, "      </trkpt>")?;
+        }
+        writeln!(self.out, "    </trkseg>")?;
+        writeln!(self.out, "  </trk>")?;
+        Ok(())
+    }
+
+    /// Close the GPX document once all activities are written
+    pub fn finish(&mut self) -> io::Result<()> {
+        writeln!(self.out, "</gpx>")?;
+        self.out.flush()
+    }
+}
+
+/// Writes the activities as Garmin Training Center XML, records grouped by lap
+pub struct TcxExport {
+    out: Box<dyn Write>,
+}
+
+impl TcxExport {
+    /// Create the export and write the TCX header
+    pub fn new(mut out: Box<dyn Write>) -> io::Result<TcxExport> {
+        writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            out,
+            concat!(
+                r#"<TrainingCenterDatabase xmlns="http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2""#,
+                r#" xmlns:ns3="http://www.garmin.com/xmlschemas/ActivityExtension/v2">"#
+            )
+        )?;
+        writeln!(out, "  <Activities>")?;
+        Ok(TcxExport { out })
+    }
+
+    /// Append one activity, records are assigned to the lap they were recorded in
+    pub fn write(&mut self, data: &[FitDataMap], opt: &Cli) -> Result<(), Box<dyn Error>> {
+        let mut clock = Clock::new(data, &opt.convert);
+        let start = start_offset(data, &opt.convert);
+        let mut records: Vec<Sample> = Vec::new();
+        for sample in samples(data, &opt.convert) {
+            let time = clock.tick(sample.timestamp).0 as u32;
+            if opt.convert.end.is_some_and(|end| time > end) {
+                break;
+            }
+            if time >= start {
+                records.push(sample);
+            }
+        }
+        let first = match records.iter().find_map(|s| s.timestamp).and_then(iso_time) {
+            Some(first) => first,
+            None => return Err("TCX export needs records with timestamps".into()),
+        };
+
+        // a record belongs to the first lap which didn't end before it
+        let laps = laps(data);
+        let mut grouped: Vec<Vec<&Sample>> = vec![Vec::new(); laps.len().max(1)];
+        let mut index = 0;
+        for sample in &records {
+            while index + 1 < laps.len() && sample.timestamp.is_some_and(|t| t > laps[index].end as f64) {
+                index += 1;
+            }
+            grouped[index].push(sample);
+        }
+
+        let sport = match sport(data).as_deref() {
+            Some("cycling") => "Biking",
+            Some("running") => "Running",
+            _ => "Other",
+        };
+        writeln!(self.out, r#"    <Activity Sport="{}">"#, sport)?;
+        writeln!(self.out, "      <Id>{}</Id>", first)?;
+        for (index, lap_records) in grouped.iter().enumerate() {
+            self.write_lap(laps.get(index), lap_records)?;
+        }
+        writeln!(self.out, "    </Activity>")?;
+        Ok(())
+    }
+
+    fn write_lap(&mut self, lap: Option<&Lap>, records: &[&Sample]) -> io::Result<()> {
+        let first = records.iter().find_map(|s| s.timestamp);
+        let last = records.iter().rev().find_map(|s| s.timestamp);
+        let start = match lap.map(|l| l.start as f64).or(first) {
+            Some(start) => start,
+            None => return Ok(()),
+        };
+        // totals from the lap message, computed from the records if there is none
+        let elapsed = lap
+            .and_then(|l| l.elapsed)
+            .unwrap_or(last.unwrap_or(start) - first.unwrap_or(start));
+        let distance = lap.and_then(|l| l.distance).unwrap_or_else(|| {
+            let first = records.iter().find_map(|s| s.distance).unwrap_or(0.0);
+            let last = records.iter().rev().find_map(|s| s.distance).unwrap_or(0.0);
+            last - first
+        });
+
+        writeln!(
+            self.out,
+            r#"      <Lap StartTime="{}">"#,
+            iso_time(start).unwrap_or_default()
+        )?;
+        writeln!(self.out, "        <TotalTimeSeconds>{:.1}</TotalTimeSeconds>", elapsed)?;
+        writeln!(self.out, "        <DistanceMeters>{:.2}</DistanceMeters>", distance)?;
+        writeln!(
+            self.out,
+            "        <Calories>{}</Calories>",
+            lap.and_then(|l| l.calories).unwrap_or(0)
+        )?;
+        writeln!(self.out, "        <Intensity>Active</Intensity>")?;
+        writeln!(self.out, "        <TriggerMethod>Manual</TriggerMethod>")?;
+        writeln!(self.out, "        <Track>")?;
+        for sample in records {
+            self.write_trackpoint(sample)?;
+        }
+        writeln!(self.out, "        </Track>")?;
+        writeln!(self.out, "      </Lap>")?;
+        Ok(())
+    }
+
+    fn write_trackpoint(&mut self, sample: &Sample) -> io::Result<()> {
+        let time = match sample.timestamp.and_then(iso_time) {
+            Some(time) => time,
+            None => return Ok(()),
+        };
+
+        let out = &mut self.out;
+        writeln!(out, "          <Trackpoint>")?;
+        writeln!(out, "            <Time>{}</Time>", time)?;
+        if let Some((lat, long)) = sample.position {
+            writeln!(out, "            <Position>")?;
+            writeln!(out, "              <LatitudeDegrees>{:.7}</LatitudeDegrees>", lat)?;
+            writeln!(out, "              <LongitudeDegrees>{:.7}</LongitudeDegrees>", long)?;
+            writeln!(out, "            </Position>")?;
+        }
+        if let Some(altitude) = sample.altitude {
+            writeln!(out, "            <AltitudeMeters>{:.1}</AltitudeMeters>", altitude)?;
+        }
+        if let Some(distance) = sample.distance {
+            writeln!(out, "            <DistanceMeters>{:.2}</DistanceMeters>", distance)?;
+        }
+        if let Some(heartrate) = sample.heartrate.filter(|hr| *hr > 0) {
+            writeln!(
+                out,
+                "            <HeartRateBpm><Value>{}</Value></HeartRateBpm>",
+                heartrate
+            )?;
+        }
+        if let Some(cadence) = sample.cadence {
+            writeln!(out, "            <Cadence>{}</Cadence>", cadence.min(254))?;
+        }
+        if let Some(power) = sample.power {
+            writeln!(
+                out,
+                "            <Extensions><ns3:TPX><ns3:Watts>{}</ns3:Watts></ns3:TPX></Extensions>",
+                power
+            )?;
+        }
+        writeln!(out, "          </Trackpoint>")?;
+        Ok(())
+    }
+
+    /// Close the TCX document once all activities are written
+    pub fn finish(&mut self) -> io::Result<()> {
+        writeln!(self.out, "  </Activities>")?;
+        writeln!(self.out, "</TrainingCenterDatabase>")?;
+        self.out.flush()
+    }
+}
+
+/// UTC time in ISO 8601 format as used by GPX and TCX
+fn iso_time(timestamp: f64) -> Option<String> {
+    // the fraction is only written for sub-second timestamps
+    let millis = (timestamp * 1000.0).round() as i64;
+    DateTime::from_timestamp(millis.div_euclid(1000), millis.rem_euclid(1000) as u32 * 1_000_000)
+        .map(|t| t.format("%Y-%m-%dT%H:%M:%S%.fZ").to_string())
+}
+
+/// Writes one CSV row per record, fields missing in a record are left empty
+pub struct CsvExport<W: Write> {
+    out: W,
+    /// columns selected by '--fields', all without
+    columns: [bool; CSV_FIELDS.len()],
+}
+
+/// Focus fields of the CSV columns, in column order
+const CSV_FIELDS: [&str; 10] = [
+    "time", "power", "cadence", "heartrate", "distance", "speed", "slope", "height", "temperature", "workKj",
+];
+
+impl<W: Write> CsvExport<W> {
+    /// Create the export and write the header row, the column names carry the units. Fails if
+    /// none of the '--fields' has a column.
+    pub fn new(out: W, units: Units, fields: Option<&Fields>) -> Result<CsvExport<W>, Box<dyn Error>> {
+        let columns = CSV_FIELDS.map(|name| fields.is_none_or(|fields| fields.contains(name)));
+        if !columns.contains(&true) {
+            let columns = CSV_FIELDS.join(", ");
+            return Err(format!("none of the --fields is a CSV column, these are: {}", columns).into());
+        }
+        let mut export = CsvExport { out, columns };
+        let header = export.row([
+            String::from("time"),
+            String::from("power"),
+            String::from("cadence"),
+            String::from("heart_rate"),
+            format!("distance_{}", units.distance_unit()),
+            format!("speed_{}", units.speed_unit()),
+            String::from("slope"),
+            format!("height_{}", units.height_unit()),
+            String::from("temperature_c"),
+            String::from("work_kj"),
+        ]);
+        writeln!(export.out, "{}", header)?;
+        Ok(export)
+    }
+
+    /// The cells of the selected columns as CSV row
+    fn row(&self, cells: [String; CSV_FIELDS.len()]) -> String {
+        let selected: Vec<String> = cells
+            .into_iter()
+            .zip(self.columns)
+            .filter_map(|(cell, selected)| selected.then_some(cell))
+            .collect();
+        selected.join(",")
+    }
+
+    /// Append all records of one activity
+    pub fn write(&mut self, data: &[FitDataMap], opt: &Cli) -> Result<(), Box<dyn Error>> {
+        let mut clock = Clock::new(data, &opt.convert);
+        let start = start_offset(data, &opt.convert);
+        // joules, including the records before '--start' like the playback metrics
+        let mut work = 0.0;
+
+        for sample in samples(data, &opt.convert) {
+            let (elapsed, dt) = clock.tick(sample.timestamp);
+            let time = elapsed as u32;
+            work += sample.power.unwrap_or(0) as f64 * dt;
+            if opt.convert.end.is_some_and(|end| time > end) {
+                break;
+            }
+            if time < start {
+                continue;
+            }
+
+            let row = self.row([
+                // whole seconds unless the device records sub-second timestamps
+                if elapsed.fract() == 0.0 { time.to_string() } else { format!("{:.3}", elapsed) },
+                cell(sample.power),
+                cell(sample.cadence),
+                cell(sample.heartrate),
+                cell(sample.distance.map(|d| format!("{:.3}", opt.units.distance(d)))),
+                cell(sample.speed.map(|s| format!("{:.2}", opt.units.speed(s)))),
+                cell(sample.grade.map(|g| format!("{:.1}", g))),
+                cell(sample.altitude.map(|a| opt.units.height(height(&opt.convert, a) as f64).round())),
+                cell(sample.temperature),
+                (work / 1000.0).round().to_string(),
+            ]);
+            writeln!(self.out, "{}", row)?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// CSV cell content, empty for missing values
+fn cell<T: Display>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
@@ -0,0 +1,216 @@
+//! InfluxDB line protocol, one 'ride' point per focus frame for graphing replays in Grafana
+//!
+//! ```text
+//! ride,rider=Anna power=250i,heartrate=150i,cadence=90i,wkg=3.4 1700000000000000000
+//! ```
+//!
+//! The rider's name is the tag, every number and flag of the frame is a field.
+use crate::output::Sink;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tpvfitplay::{Fields, Focus, Schema};
+
+const MEASUREMENT: &str = "ride";
+/// Lines per request when exporting to '--influx-url'
+const BATCH: usize = 5000;
+
+/// Line of one focus object tagged with the rider's name, None if it has no field to write
+pub fn line(rider: Option<&str>, focus: &Value, time_ns: i64) -> Option<String> {
+    let object = focus.as_object()?;
+    let mut line = String::from(MEASUREMENT);
+    if let Some(name) = rider {
+        let _ = write!(line, ",rider={}", escape(name));
+    }
+
+    let mut separator = ' ';
+    for (key, value) in object {
+        let value = match value {
+            Value::Number(n) if n.is_f64() => n.to_string(),
+            Value::Number(n) => format!("{}i", n),
+            Value::Bool(b) => b.to_string(),
+            _ => continue,
+        };
+        let _ = write!(line, "{}{}={}", separator, escape(key), value);
+        separator = ',';
+    }
+    if separator == ' ' {
+        return None;
+    }
+    let _ = write!(line, " {}", time_ns);
+    Some(line)
+}
+
+/// Tags and field keys escape commas, spaces and equal signs
+fn escape(text: &str) -> String {
+    text.replace(',', "\\,").replace(' ', "\\ ").replace('=', "\\=")
+}
+
+/// Nanoseconds since the Unix epoch of the wall clock
+fn now_ns() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| i64::try_from(d.as_nanos()).unwrap_or(i64::MAX))
+}
+
+/// InfluxDB write endpoint, e.g. 'http://localhost:8086/api/v2/write?org=me&bucket=rides'
+pub struct Endpoint {
+    url: String,
+    /// API token sent as 'Authorization: Token ...'
+    token: Option<String>,
+}
+
+impl Endpoint {
+    pub fn new(url: &str, token: Option<&str>) -> Endpoint {
+        Endpoint {
+            url: url.to_string(),
+            token: token.map(String::from),
+        }
+    }
+
+    /// Write the lines in one request
+    fn post(&self, lines: &str) -> Result<(), Box<dyn Error>> {
+        let mut request = ureq::post(&self.url).set("Content-Type", "text/plain; charset=utf-8");
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Token {}", token));
+        }
+        request
+            .send_string(lines)
+            .map_err(|err| format!("InfluxDB write failed, {}", err))?;
+        Ok(())
+    }
+}
+
+/// Where the exported lines go
+enum Destination {
+    Writer(Box<dyn Write>),
+    /// lines waiting for the next request
+    Http(Endpoint, Vec<String>),
+}
+
+/// Exports all focus frames at the time of their records, frames without a recorded
+/// timestamp get the wall clock
+pub struct InfluxExport {
+    dest: Destination,
+}
+
+impl InfluxExport {
+    pub fn new(out: Box<dyn Write>) -> InfluxExport {
+        InfluxExport {
+            dest: Destination::Writer(out),
+        }
+    }
+
+    pub fn post(endpoint: Endpoint) -> InfluxExport {
+        InfluxExport {
+            dest: Destination::Http(endpoint, Vec::new()),
+        }
+    }
+
+    /// The endpoint's URL, None if writing to a file
+    pub fn url(&self) -> Option<&str> {
+        match &self.dest {
+            Destination::Http(endpoint, _) => Some(&endpoint.url),
+            Destination::Writer(_) => None,
+        }
+    }
+
+    /// Write the lines of the frames in the '--schema', only the '--fields' if given
+    pub fn write(
+        &mut self,
+        frames: impl Iterator<Item = Focus>,
+        fields: Option<&Fields>,
+        schema: Schema,
+    ) -> Result<(), Box<dyn Error>> {
+        for focus in frames {
+            let frame = schema.frame(&focus);
+            let value = match fields {
+                Some(fields) => fields.select(&frame),
+                None => serde_json::to_value(&frame)?,
+            };
+            let time_ns = focus.timestamp.map_or_else(now_ns, |t| (t * 1e9).round() as i64);
+            let Some(line) = line(Some(&focus.name), &value, time_ns) else {
+                continue;
+            };
+            match &mut self.dest {
+                Destination::Writer(out) => writeln!(out, "{}", line)?,
+                Destination::Http(endpoint, lines) => {
+                    lines.push(line);
+                    if lines.len() >= BATCH {
+                        endpoint.post(&lines.join("\n"))?;
+                        lines.clear();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the lines still waiting
+    pub fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        match &mut self.dest {
+            Destination::Writer(out) => out.flush()?,
+            Destination::Http(endpoint, lines) => {
+                if !lines.is_empty() {
+                    endpoint.post(&lines.join("\n"))?;
+                    lines.clear();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes every played back frame to the endpoint at the wall clock time
+pub struct InfluxSink {
+    endpoint: Endpoint,
+    /// the previous write failed, warned about already
+    failing: bool,
+}
+
+impl InfluxSink {
+    pub fn new(endpoint: Endpoint) -> InfluxSink {
+        InfluxSink {
+            endpoint,
+            failing: false,
+        }
+    }
+}
+
+impl Sink for InfluxSink {
+    fn describe(&self) -> String {
+        self.endpoint.url.clone()
+    }
+
+    fn send(&mut self, frame: &str) -> Result<(), Box<dyn Error>> {
+        self.send_named(frame, &[])
+    }
+
+    fn send_named(&mut self, frame: &str, names: &[&str]) -> Result<(), Box<dyn Error>> {
+        let time_ns = now_ns();
+        let frames: Vec<Value> = serde_json::from_str(frame)?;
+        let lines: Vec<String> = frames
+            .iter()
+            .enumerate()
+            .filter_map(|(index, focus)| {
+                let rider = names.get(index).copied().or_else(|| focus.get("name").and_then(Value::as_str));
+                line(rider, focus, time_ns)
+            })
+            .collect();
+        if lines.is_empty() {
+            return Ok(());
+        }
+        // the database going away must not stop the playback
+        match self.endpoint.post(&lines.join("\n")) {
+            Err(err) if !self.failing => {
+                eprintln!("Warning: {}, frames are dropped until it works again", err);
+                self.failing = true;
+            }
+            Err(_) => {}
+            Ok(()) => self.failing = false,
+        }
+        Ok(())
+    }
+}
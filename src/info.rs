@@ -0,0 +1,88 @@
+//! The 'info' subcommand, prints a human readable summary of the activities in a FIT file
+use fitparser::profile::MesgNum;
+use fitparser::Value;
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+
+use crate::{input, Units};
+use tpvfitplay::{value_f64, value_u32, FitDataMap};
+
+/// Print the device from the 'FileId' and the totals of each 'Session' message
+pub fn run(path: &Path, units: Units) -> Result<(), Box<dyn Error>> {
+    for activity in input::read_path(path, &HashSet::new(), false, None)? {
+        let data = FitDataMap::from_records(activity.records);
+        println!("File      : {}", activity.name);
+
+        if let Some(file_id) = data.iter().find(|fdm| fdm.kind == MesgNum::FileId) {
+            let manufacturer = text(file_id, "manufacturer");
+            let product = file_id
+                .fields
+                .get("product_name")
+                .or_else(|| file_id.fields.get("garmin_product"))
+                .or_else(|| file_id.fields.get("product"))
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| String::from("--"));
+            println!("Device    : {} {}", manufacturer, product);
+            println!("Created   : {}", time(file_id, "time_created"));
+        }
+
+        let sessions: Vec<&FitDataMap> = data.iter().filter(|fdm| fdm.kind == MesgNum::Session).collect();
+        let laps = data.iter().filter(|fdm| fdm.kind == MesgNum::Lap).count();
+        if sessions.is_empty() {
+            println!("No sessions recorded, {} laps", laps);
+        }
+
+        for (index, session) in sessions.iter().enumerate() {
+            let sport = match session.fields.get("sub_sport") {
+                Some(sub_sport) => format!("{} ({})", text(session, "sport"), sub_sport),
+                None => text(session, "sport"),
+            };
+            // a single session owns all laps of the file
+            let num_laps = session
+                .fields
+                .get("num_laps")
+                .and_then(value_u32)
+                .map_or(laps, |n| n as usize);
+
+            println!("Session {}", index + 1);
+            println!("  Start   : {}", time(session, "start_time"));
+            println!("  Elapsed : {}", duration(session.fields.get("total_elapsed_time").and_then(value_f64)));
+            match session.fields.get("total_distance").and_then(value_f64) {
+                Some(distance) => println!("  Distance: {:.2} {}", units.distance(distance), units.distance_unit()),
+                None => println!("  Distance: --"),
+            }
+            println!("  Sport   : {}", sport);
+            println!("  Laps    : {}", num_laps);
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Field value as text, '--' if missing
+pub fn text(fdm: &FitDataMap, name: &str) -> String {
+    fdm.fields
+        .get(name)
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| String::from("--"))
+}
+
+/// Timestamp field in local time, '--' if missing
+pub fn time(fdm: &FitDataMap, name: &str) -> String {
+    match fdm.fields.get(name) {
+        Some(Value::Timestamp(t)) => t.format("%Y-%m-%d %H:%M:%S").to_string(),
+        _ => String::from("--"),
+    }
+}
+
+/// Seconds as H:MM:SS, '--' if missing
+pub fn duration(seconds: Option<f64>) -> String {
+    match seconds {
+        Some(seconds) => {
+            let seconds = seconds as u64;
+            format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+        }
+        None => String::from("--"),
+    }
+}
@@ -0,0 +1,357 @@
+//! Reading the FIT data from the inputs
+use fitparser::de::{from_bytes_with_options, DecodeOption, FitDataMessage, FitObject, FitStreamProcessor};
+use fitparser::profile::MesgNum;
+use fitparser::FitDataRecord;
+use flate2::read::GzDecoder;
+use memmap2::Mmap;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use tpvfitplay::{value_timestamp, Focus};
+use zip::ZipArchive;
+
+/// First two bytes of any gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Messages the playback and the exports use, the records and what the laps, sessions,
+/// pauses, sport and FTP are read from
+pub const PLAYBACK_MESSAGES: &[MesgNum] = &[
+    MesgNum::FileId,
+    MesgNum::Record,
+    MesgNum::Lap,
+    MesgNum::Session,
+    MesgNum::Event,
+    MesgNum::Sport,
+    MesgNum::UserProfile,
+    MesgNum::ZonesTarget,
+];
+
+/// Files of at least this many bytes are decoded from a memory map instead of being read
+const MMAP_MIN_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Records of one FIT file, `name` tells where they were read from
+pub struct Activity {
+    pub name: String,
+    /// file name without directory and extensions, e.g. 'ride_1'
+    pub stem: String,
+    pub records: Vec<FitDataRecord>,
+}
+
+/// File name of `path` without directory and FIT/gzip extensions
+pub fn stem(path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut stem = name.as_str();
+    for ext in [".gz", ".fit"] {
+        if has_extension(stem, &[ext]) {
+            stem = &stem[..stem.len() - ext.len()];
+        }
+    }
+    stem.to_string()
+}
+
+/// Expand the arguments with '*' or '?' which don't name an existing file as glob patterns,
+/// for shells which pass them on literally (e.g. Windows 'cmd'). A pattern matching nothing is
+/// an error.
+pub fn expand_globs(files: Vec<PathBuf>) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut expanded = Vec::new();
+    for file in files {
+        let pattern = file.to_string_lossy();
+        if file.exists() || !pattern.contains(['*', '?']) {
+            expanded.push(file);
+            continue;
+        }
+        let matches = glob::glob(&pattern)
+            .map_err(|err| format!("invalid pattern {:?}: {}", pattern, err))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if matches.is_empty() {
+            return Err(format!("no files match {:?}", pattern).into());
+        }
+        expanded.extend(matches);
+    }
+    Ok(expanded)
+}
+
+/// Read the FIT file at `path`, zip archives and directories yield all FIT files they
+/// contain. With `recover` the records up to a decode error are kept instead of failing,
+/// with `messages` only these kinds are decoded instead of all.
+pub fn read_path(
+    path: &Path,
+    decode_opts: &HashSet<DecodeOption>,
+    recover: bool,
+    messages: Option<&[MesgNum]>,
+) -> Result<Vec<Activity>, Box<dyn Error>> {
+    if path.is_dir() {
+        return read_dir(path, decode_opts, recover, messages);
+    }
+    if has_extension(&path.to_string_lossy(), &[".zip"]) {
+        return read_zip(path, decode_opts, recover, messages);
+    }
+
+    let name = path.display().to_string();
+    let mut fp = File::open(path)?;
+    let records = if fp.metadata()?.len() >= MMAP_MIN_SIZE {
+        // SAFETY: the map is only read while decoding, a file truncated meanwhile by another
+        // process is undefined behaviour which the file system doesn't protect against either
+        let map = unsafe { Mmap::map(&fp)? };
+        decode_fit(&map, &name, decode_opts, recover, messages)?
+    } else {
+        read_fit(&mut fp, &name, decode_opts, recover, messages)?
+    };
+    Ok(vec![Activity {
+        name,
+        stem: stem(path),
+        records,
+    }])
+}
+
+/// Read all FIT files in a zip archive in the order they are stored, other entries are skipped
+fn read_zip(
+    path: &Path,
+    decode_opts: &HashSet<DecodeOption>,
+    recover: bool,
+    messages: Option<&[MesgNum]>,
+) -> Result<Vec<Activity>, Box<dyn Error>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut activities = Vec::new();
+
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let name = format!("{}:{}", path.display(), entry.name());
+        if !has_extension(entry.name(), &[".fit", ".fit.gz"]) {
+            eprintln!("Warning: skipping {}, not a FIT file", name);
+            continue;
+        }
+        let stem = stem(Path::new(entry.name()));
+        let records = read_fit(&mut entry, &name, decode_opts, recover, messages)?;
+        activities.push(Activity {
+            name,
+            stem,
+            records,
+        });
+    }
+    Ok(activities)
+}
+
+/// Read all FIT files below the directory `path` ordered by their start time, other files are
+/// ignored and files which can't be read are skipped
+fn read_dir(
+    path: &Path,
+    decode_opts: &HashSet<DecodeOption>,
+    recover: bool,
+    messages: Option<&[MesgNum]>,
+) -> Result<Vec<Activity>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    find_fit_files(path, &mut files)?;
+
+    // parsing is independent per file
+    let read: Vec<Result<Vec<Activity>, String>> = files
+        .par_iter()
+        .map(|file| read_path(file, decode_opts, recover, messages).map_err(|err| err.to_string()))
+        .collect();
+
+    let mut activities = Vec::new();
+    for (file, result) in files.iter().zip(read) {
+        match result {
+            Ok(read) => activities.extend(read),
+            Err(err) => eprintln!("Error: skipping {:?}: {}", file, err),
+        }
+    }
+    if activities.is_empty() {
+        return Err(format!("no FIT files could be read in {:?}", path).into());
+    }
+    activities.sort_by_key(|activity| start_time(&activity.records).unwrap_or(i64::MAX));
+    Ok(activities)
+}
+
+/// Collect the FIT files in `dir` and its subdirectories, each directory in name order
+fn find_fit_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            find_fit_files(&entry, files)?;
+        } else if has_extension(&entry.to_string_lossy(), &[".fit", ".fit.gz"]) {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+/// Timestamp of the first 'Record' message, of the first message with a timestamp if there is
+/// none
+fn start_time(records: &[FitDataRecord]) -> Option<i64> {
+    let timestamp = |record: &FitDataRecord| {
+        record
+            .fields()
+            .iter()
+            .find(|f| f.name() == "timestamp")
+            .and_then(|f| value_timestamp(f.value()))
+    };
+    records
+        .iter()
+        .filter(|record| record.kind() == MesgNum::Record)
+        .find_map(timestamp)
+        .or_else(|| records.iter().find_map(timestamp))
+}
+
+/// Case insensitive check of the file name extension
+fn has_extension(name: &str, extensions: &[&str]) -> bool {
+    let name = name.to_lowercase();
+    extensions.iter().any(|ext| name.ends_with(ext))
+}
+
+/// Parse all FIT data from `source` named `name`, gzip compressed data is decompressed first
+pub fn read_fit(
+    source: &mut dyn Read,
+    name: &str,
+    decode_opts: &HashSet<DecodeOption>,
+    recover: bool,
+    messages: Option<&[MesgNum]>,
+) -> Result<Vec<FitDataRecord>, Box<dyn Error>> {
+    let mut buffer = Vec::new();
+    source.read_to_end(&mut buffer)?;
+    decode_fit(&buffer, name, decode_opts, recover, messages)
+}
+
+/// Parse all FIT data in `buffer`, read or memory mapped, gzip compressed data is
+/// decompressed first
+fn decode_fit(
+    buffer: &[u8],
+    name: &str,
+    decode_opts: &HashSet<DecodeOption>,
+    recover: bool,
+    messages: Option<&[MesgNum]>,
+) -> Result<Vec<FitDataRecord>, Box<dyn Error>> {
+    let decompressed;
+    let buffer = if buffer.starts_with(&GZIP_MAGIC) {
+        let mut data = Vec::new();
+        GzDecoder::new(buffer).read_to_end(&mut data)?;
+        decompressed = data;
+        &decompressed[..]
+    } else {
+        buffer
+    };
+    if !recover && messages.is_none() {
+        return Ok(from_bytes_with_options(buffer, decode_opts)?);
+    }
+
+    let (records, decoded, err) = read_partial(buffer, decode_opts, messages);
+    if let Some(err) = err {
+        if !recover || records.is_empty() {
+            return Err(err.into());
+        }
+        eprintln!(
+            "Warning: {}: {}, recovered {} records from {} of {} bytes",
+            name,
+            err,
+            records.len(),
+            decoded,
+            buffer.len()
+        );
+    }
+    Ok(records)
+}
+
+/// Bytes decoded before the first error and the size of the decompressed FIT file at `path`,
+/// None if it decodes or is a directory or zip archive
+pub fn error_offset(
+    path: &Path,
+    decode_opts: &HashSet<DecodeOption>,
+) -> Result<Option<(usize, usize)>, Box<dyn Error>> {
+    if path.is_dir() || has_extension(&path.to_string_lossy(), &[".zip"]) {
+        return Ok(None);
+    }
+    let mut buffer = fs::read(path)?;
+    if buffer.starts_with(&GZIP_MAGIC) {
+        let mut data = Vec::new();
+        GzDecoder::new(&buffer[..]).read_to_end(&mut data)?;
+        buffer = data;
+    }
+    let (_, decoded, err) = read_partial(&buffer, decode_opts, None);
+    Ok(err.map(|_| (decoded, buffer.len())))
+}
+
+/// Decode the messages one by one up to the first error, returns the records, the number of
+/// bytes they were decoded from and the error. Messages of kinds not in `messages` are
+/// dropped, without decoding them if possible.
+fn read_partial(
+    buffer: &[u8],
+    decode_opts: &HashSet<DecodeOption>,
+    messages: Option<&[MesgNum]>,
+) -> (Vec<FitDataRecord>, usize, Option<fitparser::Error>) {
+    let wanted = |kind: MesgNum| messages.is_none_or(|messages| messages.contains(&kind));
+    let mut processor = FitStreamProcessor::new();
+    decode_opts.iter().for_each(|o| processor.add_option(*o));
+    let drop_unknown = decode_opts.contains(&DecodeOption::DropUnknownMessages);
+
+    let mut records = Vec::new();
+    let mut remaining = buffer;
+    while !remaining.is_empty() {
+        let (rest, obj) = match processor.deserialize_next(remaining) {
+            Ok(next) => next,
+            Err(err) => return (records, buffer.len() - remaining.len(), Some(err)),
+        };
+        match obj {
+            FitObject::Crc(..) => processor.reset(),
+            FitObject::DataMessage(msg) if !must_decode(&msg, wanted) => {}
+            FitObject::DataMessage(msg) => match processor.decode_message(msg) {
+                Ok(record) if drop_unknown && !MesgNum::is_named_variant(record.kind().as_i64()) => {}
+                Ok(record) if !wanted(record.kind()) => {}
+                Ok(record) => records.push(record),
+                Err(err) => return (records, buffer.len() - remaining.len(), Some(err)),
+            },
+            FitObject::Header(..) | FitObject::DefinitionMessage(..) => {}
+        }
+        remaining = rest;
+    }
+    (records, buffer.len(), None)
+}
+
+/// False for a message which can be skipped without decoding it: one not `wanted` which
+/// doesn't define a developer field or carry a timestamp, the timestamp is the reference of
+/// the compressed timestamps of the following messages
+fn must_decode(msg: &FitDataMessage, wanted: impl Fn(MesgNum) -> bool) -> bool {
+    let kind = MesgNum::from(msg.global_message_number());
+    wanted(kind)
+        || matches!(kind, MesgNum::DeveloperDataId | MesgNum::FieldDescription)
+        || msg.fields().contains_key(&253)
+}
+
+/// Read the focus frames of an 'array' or 'ndjson' export, fields missing in a frame keep
+/// their default, unknown fields are an error
+pub fn read_focus(path: &Path) -> Result<Vec<Focus>, Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+
+    let mut frames: Vec<Focus> = if content.trim_start().starts_with('[') {
+        serde_json::from_str(&content).map_err(|err| format!("invalid focus array {:?}: {}", path, err))?
+    } else {
+        content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                serde_json::from_str(line)
+                    .map_err(|err| format!("invalid focus in {:?} line {}: {}", path, index + 1, err))
+            })
+            .collect::<Result<_, String>>()?
+    };
+    // dumps written before 'timeMs' only keep the whole seconds to pace by
+    for focus in frames.iter_mut() {
+        focus.elapsed = match focus.timeMs {
+            0 => focus.time as f64,
+            ms => ms as f64 / 1000.0,
+        };
+    }
+    Ok(frames)
+}
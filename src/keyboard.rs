@@ -0,0 +1,261 @@
+//! Keyboard control of the playback, pressing space pauses and resumes, Ctrl-C stops it.
+//! With '--step' Enter or the right arrow sends the next frame, the left arrow the previous.
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Read};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+/// Key press of '--step'
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    Forward,
+    Back,
+}
+
+/// Pause state shared between the keyboard reader, the HTTP control routes and the
+/// playback loop
+#[derive(Clone, Debug)]
+pub struct Pause {
+    paused: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    /// true while the playback can jump to another time
+    seekable: Arc<AtomicBool>,
+    /// recorded time to jump to, taken by the playback loop
+    seek: Arc<Mutex<Option<u32>>>,
+    /// recorded time of the frame sent last
+    position: Arc<AtomicU32>,
+    /// steps pressed but not taken by the playback yet
+    steps: Arc<Mutex<VecDeque<Step>>>,
+    player: Thread,
+}
+
+impl Pause {
+    /// Create the pause state for the calling (playback) thread
+    pub fn new() -> Pause {
+        Pause {
+            paused: Arc::new(AtomicBool::new(false)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            seekable: Arc::new(AtomicBool::new(false)),
+            seek: Arc::new(Mutex::new(None)),
+            position: Arc::new(AtomicU32::new(0)),
+            steps: Arc::new(Mutex::new(VecDeque::new())),
+            player: thread::current(),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Flip between paused and playing, returns the new paused state
+    pub fn toggle(&self) -> bool {
+        let paused = !self.paused.fetch_xor(true, Ordering::SeqCst);
+        if !paused {
+            self.player.unpark();
+        }
+        paused
+    }
+
+    /// Pause or resume the playback
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        if !paused {
+            self.player.unpark();
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// End the playback after the current frame, wakes up the playback thread if it is
+    /// paused or waiting for the next frame
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.player.unpark();
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        self.seekable.load(Ordering::SeqCst)
+    }
+
+    /// Allow seeking or not, a seek not taken before is dropped
+    pub fn set_seekable(&self, seekable: bool) {
+        self.seekable.store(seekable, Ordering::SeqCst);
+        if !seekable {
+            self.take_seek();
+        }
+    }
+
+    /// Ask the playback to continue at recorded `time`, false if it can't seek right now
+    pub fn seek(&self, time: u32) -> bool {
+        if !self.is_seekable() {
+            return false;
+        }
+        if let Ok(mut seek) = self.seek.lock() {
+            *seek = Some(time);
+        }
+        self.player.unpark();
+        true
+    }
+
+    /// True if a seek is waiting to be taken by the playback loop
+    pub fn is_seeking(&self) -> bool {
+        self.seek.lock().is_ok_and(|seek| seek.is_some())
+    }
+
+    /// Recorded time to continue at, if a seek was asked for since the last call
+    pub fn take_seek(&self) -> Option<u32> {
+        self.seek.lock().ok().and_then(|mut seek| seek.take())
+    }
+
+    /// Recorded time of the frame sent last
+    pub fn position(&self) -> u32 {
+        self.position.load(Ordering::SeqCst)
+    }
+
+    pub fn set_position(&self, time: u32) {
+        self.position.store(time, Ordering::SeqCst);
+    }
+
+    /// Queue a step for the playback
+    pub fn step(&self, step: Step) {
+        if let Ok(mut steps) = self.steps.lock() {
+            steps.push_back(step);
+        }
+        self.player.unpark();
+    }
+
+    /// Block the playback thread until a step was pressed, returns None without a step if
+    /// playback was stopped or asked to seek meanwhile
+    pub fn wait_step(&self) -> Option<Step> {
+        loop {
+            if let Some(step) = self.steps.lock().ok().and_then(|mut steps| steps.pop_front()) {
+                return Some(step);
+            }
+            if self.is_stopped() || self.is_seeking() {
+                return None;
+            }
+            thread::park_timeout(Duration::from_secs(1));
+        }
+    }
+
+    /// Block the playback thread as long as playback is paused, true if it was paused
+    pub fn wait(&self) -> bool {
+        let mut waited = false;
+        while self.is_paused() && !self.is_stopped() {
+            // parked until resumed, the timeout only guards against missed wakeups
+            thread::park_timeout(Duration::from_secs(1));
+            waited = true;
+        }
+        waited
+    }
+}
+
+/// Start reading single key presses from the terminal in a background thread.
+///
+/// Nothing is done if stdin is not a terminal, e.g. when the FIT data is piped in, false
+/// then.
+pub fn spawn(pause: &Pause) -> bool {
+    if !io::stdin().is_terminal() || !term::enable_raw() {
+        return false;
+    }
+
+    let pause = pause.clone();
+    thread::spawn(move || {
+        let mut key = [0u8; 1];
+        // the arrow keys arrive as 'ESC [ C' (right) and 'ESC [ D' (left)
+        let mut escape = 0;
+        while let Ok(1) = io::stdin().read(&mut key) {
+            escape = match (escape, key[0]) {
+                (_, 0x1b) => 1,
+                (1, b'[') => 2,
+                (2, b'C') | (0, b'\n' | b'\r') => {
+                    pause.step(Step::Forward);
+                    0
+                }
+                (2, b'D') => {
+                    pause.step(Step::Back);
+                    0
+                }
+                (0, b' ') => {
+                    pause.toggle();
+                    0
+                }
+                _ => 0,
+            };
+        }
+    });
+    true
+}
+
+/// Handle Ctrl-C: a `stoppable` playback is stopped to write its final frame, anything
+/// else (or pressing Ctrl-C again) exits at once
+pub fn handle_interrupt(pause: &Pause, stoppable: bool) {
+    let pause = pause.clone();
+    if let Err(err) = ctrlc::set_handler(move || {
+        if stoppable && !pause.is_stopped() {
+            pause.stop();
+            return;
+        }
+        term::restore();
+        println!();
+        std::process::exit(130);
+    }) {
+        eprintln!("Warning: unable to install Ctrl-C handler: {}", err);
+    }
+}
+
+/// Put the terminal back into its original mode
+pub fn restore() {
+    term::restore();
+}
+
+#[cfg(unix)]
+mod term {
+    use std::sync::Mutex;
+
+    static ORIGINAL: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+    /// Switch off line buffering and echo, signals like Ctrl-C stay enabled
+    pub fn enable_raw() -> bool {
+        unsafe {
+            let mut attrs: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut attrs) != 0 {
+                return false;
+            }
+            let original = attrs;
+            attrs.c_lflag &= !(libc::ICANON | libc::ECHO);
+            attrs.c_cc[libc::VMIN] = 1;
+            attrs.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &attrs) != 0 {
+                return false;
+            }
+            if let Ok(mut saved) = ORIGINAL.lock() {
+                *saved = Some(original);
+            }
+        }
+        true
+    }
+
+    pub fn restore() {
+        if let Ok(mut saved) = ORIGINAL.lock() {
+            if let Some(original) = saved.take() {
+                unsafe {
+                    libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod term {
+    pub fn enable_raw() -> bool {
+        false
+    }
+
+    pub fn restore() {}
+}
@@ -0,0 +1,1909 @@
+//! Conversion of FIT records into TPV 'focus.json' frames, the 'tpvfitplay' binary plays
+//! these back or exports them
+use fitparser::profile::MesgNum;
+use fitparser::{FitDataRecord, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::str::FromStr;
+use structopt::StructOpt;
+
+pub mod mapping;
+pub mod wind;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+#[allow(non_snake_case)]
+pub struct Focus {
+    pub name: String,
+    pub country: String,
+    pub team: String,
+    pub teamCode: String,
+    pub power: u32,
+    pub powerBalance: u32,
+    pub powerZone: u32,
+    pub wkg: f32,
+    pub avgPower: u32,
+    pub nrmPower: u32,
+    pub maxPower: u32,
+    pub cadence: u32,
+    pub avgCadence: u32,
+    pub maxCadence: u32,
+    pub heartrate: u32,
+    pub hrZone: u32,
+    pub avgHeartrate: u32,
+    pub maxHeartrate: u32,
+    pub time: u32,
+    /// elapsed milliseconds, 'time' only has whole seconds
+    pub timeMs: u32,
+    pub movingTime: u32,
+    pub distance: u32,
+    pub height: u32,
+    pub totalAscent: u32,
+    pub totalDescent: u32,
+    /// meters climbed per hour over the '--vam-window', 0 on flat and descending sections
+    pub vam: u32,
+    pub speed: u32,
+    /// over the moving time, scaled like 'speed'
+    pub avgSpeed: u32,
+    pub maxSpeed: u32,
+    pub tss: u32,
+    pub intensityFactor: u32,
+    pub variabilityIndex: u32,
+    /// normalized power over the average heart rate, times 100 like 'intensityFactor'
+    pub efficiencyFactor: u32,
+    pub calories: u32,
+    /// mechanical work done so far
+    pub workKj: u32,
+    /// W' balance in joules, the anaerobic work capacity left, 0 without '--cp' and '--wprime'
+    pub wBal: i32,
+    pub draft: u32,
+    pub windSpeed: u32,
+    pub windAngle: u32,
+    pub slope: i32,
+    pub temperature: i32,
+    pub lat: f64,
+    pub long: f64,
+    pub hasPosition: bool,
+    pub eventLapsTotal: u32,
+    pub eventLapsDone: i32,
+    pub eventDistanceTotal: u32,
+    pub eventDistanceDone: u32,
+    pub eventDistanceToNextLocation: u32,
+    pub eventNextLocation: u32,
+    pub eventPosition: u32,
+    /// set in the last frame written when playback is stopped by Ctrl-C
+    pub finished: bool,
+    /// precise elapsed seconds behind 'time', used for pacing
+    #[serde(skip)]
+    pub elapsed: f64,
+    /// Unix time of the record in seconds, None if it carries no timestamp
+    #[serde(skip)]
+    pub timestamp: Option<f64>,
+}
+
+impl Focus {
+    pub fn new() -> Focus {
+        Focus {
+            name: String::from("--"),
+            country:  String::from("--"),
+            team: String::from("--"),
+            teamCode: String::from("--"),
+            power: 0,
+            powerBalance: 50,
+            powerZone: 0,
+            wkg: 0.0,
+            avgPower: 0,
+            nrmPower: 0,
+            maxPower: 0,
+            cadence: 0,
+            avgCadence: 0,
+            maxCadence: 0,
+            heartrate: 0,
+            hrZone: 0,
+            avgHeartrate: 0,
+            maxHeartrate: 0,
+            time: 0,
+            timeMs: 0,
+            movingTime: 0,
+            distance: 0,
+            height: 0,
+            totalAscent: 0,
+            totalDescent: 0,
+            vam: 0,
+            speed: 0,
+            avgSpeed: 0,
+            maxSpeed: 0,
+            tss: 0,
+            intensityFactor: 0,
+            variabilityIndex: 0,
+            efficiencyFactor: 0,
+            calories: 0,
+            workKj: 0,
+            wBal: 0,
+            draft: 0,
+            windSpeed: 0,
+            windAngle: 0,
+            slope: 0,
+            temperature: 0,
+            lat: 0.0,
+            long: 0.0,
+            hasPosition: false,
+            eventLapsTotal: 0,
+            eventLapsDone: 0,
+            eventDistanceTotal: 0,
+            eventDistanceDone: 0,
+            eventDistanceToNextLocation: 0,
+            eventNextLocation: 0,
+            eventPosition: 0,
+            finished: false,
+            elapsed: 0.0,
+            timestamp: None,
+        }
+    }
+}
+
+impl Default for Focus {
+    fn default() -> Focus {
+        Focus::new()
+    }
+}
+
+/// Field set of the focus frames, selected with '--schema' for older overlays
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Schema {
+    /// the fields of the first TPV releases
+    V1,
+    /// all fields
+    #[default]
+    V2,
+}
+
+impl Schema {
+    /// The focus in this schema, ready to serialize
+    pub fn frame<'a>(&self, focus: &'a Focus) -> Frame<'a> {
+        match self {
+            Schema::V1 => Frame::V1(FocusV1::from(focus)),
+            Schema::V2 => Frame::V2(focus),
+        }
+    }
+
+    /// All keys of a serialized focus in this schema in their order
+    pub fn names(&self) -> Vec<String> {
+        match serde_json::to_value(self.frame(&Focus::new())) {
+            Ok(serde_json::Value::Object(map)) => map.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl FromStr for Schema {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Schema, String> {
+        match s {
+            "v1" => Ok(Schema::V1),
+            "v2" => Ok(Schema::V2),
+            _ => Err(format!("unknown schema '{}'", s)),
+        }
+    }
+}
+
+/// Focus as serialized in one of the schemas
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Frame<'a> {
+    V1(FocusV1<'a>),
+    V2(&'a Focus),
+}
+
+/// Focus of schema v1, the fields the first TPV releases broadcast
+#[derive(Debug, Serialize)]
+#[allow(non_snake_case)]
+pub struct FocusV1<'a> {
+    name: &'a str,
+    country: &'a str,
+    team: &'a str,
+    teamCode: &'a str,
+    power: u32,
+    avgPower: u32,
+    nrmPower: u32,
+    maxPower: u32,
+    cadence: u32,
+    avgCadence: u32,
+    maxCadence: u32,
+    heartrate: u32,
+    avgHeartrate: u32,
+    maxHeartrate: u32,
+    time: u32,
+    distance: u32,
+    height: u32,
+    speed: u32,
+    tss: u32,
+    calories: u32,
+    draft: u32,
+    windSpeed: u32,
+    windAngle: u32,
+    slope: i32,
+    eventLapsTotal: u32,
+    eventLapsDone: i32,
+    eventDistanceTotal: u32,
+    eventDistanceDone: u32,
+    eventDistanceToNextLocation: u32,
+    eventNextLocation: u32,
+    eventPosition: u32,
+}
+
+impl<'a> From<&'a Focus> for FocusV1<'a> {
+    fn from(focus: &'a Focus) -> FocusV1<'a> {
+        FocusV1 {
+            name: &focus.name,
+            country: &focus.country,
+            team: &focus.team,
+            teamCode: &focus.teamCode,
+            power: focus.power,
+            avgPower: focus.avgPower,
+            nrmPower: focus.nrmPower,
+            maxPower: focus.maxPower,
+            cadence: focus.cadence,
+            avgCadence: focus.avgCadence,
+            maxCadence: focus.maxCadence,
+            heartrate: focus.heartrate,
+            avgHeartrate: focus.avgHeartrate,
+            maxHeartrate: focus.maxHeartrate,
+            time: focus.time,
+            distance: focus.distance,
+            height: focus.height,
+            speed: focus.speed,
+            tss: focus.tss,
+            calories: focus.calories,
+            draft: focus.draft,
+            windSpeed: focus.windSpeed,
+            windAngle: focus.windAngle,
+            slope: focus.slope,
+            eventLapsTotal: focus.eventLapsTotal,
+            eventLapsDone: focus.eventLapsDone,
+            eventDistanceTotal: focus.eventDistanceTotal,
+            eventDistanceDone: focus.eventDistanceDone,
+            eventDistanceToNextLocation: focus.eventDistanceToNextLocation,
+            eventNextLocation: focus.eventNextLocation,
+            eventPosition: focus.eventPosition,
+        }
+    }
+}
+
+/// Focus keys to serialize, parsed from the comma separated '--fields' list
+#[derive(Clone, Debug)]
+pub struct Fields(Vec<String>);
+
+impl Fields {
+    /// All keys of a serialized focus in their order
+    pub fn names() -> Vec<String> {
+        Schema::V2.names()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|field| field == name)
+    }
+
+    /// First selected key the schema doesn't have
+    pub fn missing(&self, schema: Schema) -> Option<&str> {
+        let names = schema.names();
+        self.0.iter().find(|field| !names.contains(field)).map(String::as_str)
+    }
+
+    /// The focus (or `Frame`) as JSON object with only the selected keys, in the order of the
+    /// focus
+    pub fn select<T: Serialize + ?Sized>(&self, focus: &T) -> serde_json::Value {
+        let mut value = serde_json::to_value(focus).unwrap_or_default();
+        if let serde_json::Value::Object(map) = &mut value {
+            map.retain(|name, _| self.contains(name));
+        }
+        value
+    }
+}
+
+impl FromStr for Fields {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Fields, String> {
+        let names = Fields::names();
+        let fields: Vec<String> = s
+            .split(',')
+            .map(|field| field.trim().to_string())
+            .filter(|field| !field.is_empty())
+            .collect();
+        if let Some(unknown) = fields.iter().find(|field| !names.contains(field)) {
+            return Err(format!("unknown field '{}', valid fields are: {}", unknown, names.join(", ")));
+        }
+        if fields.is_empty() {
+            return Err(String::from("no fields given"));
+        }
+        Ok(Fields(fields))
+    }
+}
+
+/// Rider identity as read from the '--rider' JSON file, missing keys keep the default
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(non_snake_case)]
+pub struct Rider {
+    pub name: Option<String>,
+    pub country: Option<String>,
+    pub team: Option<String>,
+    pub teamCode: Option<String>,
+}
+
+impl Rider {
+
+    /// Copy the known identity fields over to the focus
+    pub fn apply(&self, focus: &mut Focus) {
+        if let Some(name) = &self.name {
+            focus.name = name.clone();
+        }
+        if let Some(country) = &self.country {
+            focus.country = country.clone();
+        }
+        if let Some(team) = &self.team {
+            focus.team = team.clone();
+        }
+        if let Some(team_code) = &self.teamCode {
+            focus.teamCode = team_code.clone();
+        }
+    }
+}
+
+/// Unsigned integer content of a FIT value of any numeric type, floats are rounded (some
+/// devices record e.g. the power as float), negative values are 0 and values too large
+/// saturate, None for non numeric values and NaN
+pub fn value_u32(value: &Value) -> Option<u32> {
+    match *value {
+        Value::Byte(v) | Value::UInt8(v) | Value::UInt8z(v) => Some(v as u32),
+        Value::UInt16(v) | Value::UInt16z(v) => Some(v as u32),
+        Value::UInt32(v) | Value::UInt32z(v) => Some(v),
+        Value::UInt64(v) | Value::UInt64z(v) => Some(u32::try_from(v).unwrap_or(u32::MAX)),
+        Value::SInt8(v) => Some(v.max(0) as u32),
+        Value::SInt16(v) => Some(v.max(0) as u32),
+        Value::SInt32(v) => Some(v.max(0) as u32),
+        Value::SInt64(v) => Some(u32::try_from(v.max(0)).unwrap_or(u32::MAX)),
+        Value::Float32(_) | Value::Float64(_) => value_f64(value).map(|v| clamp_u32(v.round())),
+        _ => None,
+    }
+}
+
+/// Numeric content of a FIT value as float, None for non numeric values
+pub fn value_f64(value: &Value) -> Option<f64> {
+    match *value {
+        // a glitching sensor may send NaN or infinity, the field counts as missing then
+        Value::Float32(v) if v.is_finite() => Some(v as f64),
+        Value::Float64(v) if v.is_finite() => Some(v),
+        Value::Byte(v) | Value::UInt8(v) | Value::UInt8z(v) => Some(v as f64),
+        Value::SInt8(v) => Some(v as f64),
+        Value::UInt16(v) | Value::UInt16z(v) => Some(v as f64),
+        Value::SInt16(v) => Some(v as f64),
+        Value::UInt32(v) | Value::UInt32z(v) => Some(v as f64),
+        Value::SInt32(v) => Some(v as f64),
+        Value::UInt64(v) | Value::UInt64z(v) => Some(v as f64),
+        Value::SInt64(v) => Some(v as f64),
+        _ => None,
+    }
+}
+
+/// Float as unsigned focus value, negative values are 0 and values too large saturate
+pub fn clamp_u32(value: f64) -> u32 {
+    value.clamp(0.0, u32::MAX as f64) as u32
+}
+
+/// Float as signed focus value, values out of range saturate
+pub fn clamp_i32(value: f64) -> i32 {
+    value.clamp(i32::MIN as f64, i32::MAX as f64) as i32
+}
+
+/// Seconds of the rolling window used for normalized power
+const NP_WINDOW: f64 = 30.0;
+
+/// Running accumulators for the metrics derived while playing back the records
+#[derive(Debug, Default)]
+struct Metrics {
+    power_sum: u64,
+    power_count: u64,
+    power_max: u32,
+    /// elapsed seconds and power of the records in the rolling window
+    np_window: VecDeque<(f64, u32)>,
+    np_window_sum: u64,
+    np_start: Option<f64>,
+    np_sum4: f64,
+    np_count: u64,
+    cadence_exclude_zeros: bool,
+    ftp: Option<u32>,
+    cadence_sum: u64,
+    cadence_count: u64,
+    cadence_max: u32,
+    heartrate_sum: u64,
+    heartrate_count: u64,
+    heartrate_max: u32,
+    work_joules: f64,
+    /// km/h
+    moving_threshold: f64,
+    moving_seconds: f64,
+    /// meters and seconds moved at a known speed, for the average speed
+    moving_distance: f64,
+    speed_seconds: f64,
+    /// m/s
+    speed_max: f64,
+    /// seconds
+    smooth_window: u32,
+    smooth: VecDeque<(f64, u32)>,
+    /// meters
+    elevation_deadband: f64,
+    elevation_ref: Option<f64>,
+    /// kg
+    weight: Option<f64>,
+    ascent: f64,
+    descent: f64,
+    /// seconds
+    vam_window: f64,
+    /// elapsed seconds and altitude of the records within the VAM window
+    vam_altitudes: VecDeque<(f64, f64)>,
+    /// critical power in watts and W' in joules
+    wprime: Option<(f64, f64)>,
+    /// W' spent above the critical power, decayed by the recovery since
+    wprime_spent: f64,
+    /// watts below the critical power times seconds, and the seconds, for the recovery
+    below_cp: f64,
+    below_cp_seconds: f64,
+}
+
+impl Metrics {
+    fn new(opt: &ConvertOptions, ftp: Option<u32>) -> Metrics {
+        Metrics {
+            cadence_exclude_zeros: opt.cadence_exclude_zeros,
+            ftp,
+            moving_threshold: opt.moving_threshold,
+            smooth_window: opt.power_smooth,
+            elevation_deadband: opt.elevation_deadband,
+            weight: opt.weight,
+            vam_window: opt.vam_window as f64,
+            wprime: opt.cp.zip(opt.wprime).map(|(cp, wprime)| (cp as f64, wprime as f64)),
+            ..Metrics::default()
+        }
+    }
+
+    /// Account for a record at `elapsed` seconds which carried a power value
+    fn add_power(&mut self, elapsed: f64, power: u32) {
+        self.power_sum += power as u64;
+        self.power_count += 1;
+        self.power_max = self.power_max.max(power);
+
+        // rolling 30s average over the records of the last 30 seconds whatever their rate,
+        // each record from the 30th second on contributes the 4th power of its window
+        self.np_window.push_back((elapsed, power));
+        self.np_window_sum += power as u64;
+        while self.np_window.front().is_some_and(|(t, _)| *t <= elapsed - NP_WINDOW) {
+            self.np_window_sum -= self.np_window.pop_front().map_or(0, |(_, p)| p as u64);
+        }
+        if elapsed - *self.np_start.get_or_insert(elapsed) >= NP_WINDOW - 1.0 {
+            let rolling = self.np_window_sum as f64 / self.np_window.len() as f64;
+            self.np_sum4 += rolling.powi(4);
+            self.np_count += 1;
+        }
+    }
+
+    /// Mean of the power over the last `--power-smooth` seconds up to `elapsed`, the power
+    /// itself for a window of one second
+    fn smooth_power(&mut self, elapsed: f64, power: u32) -> u32 {
+        if self.smooth_window <= 1 {
+            return power;
+        }
+        self.smooth.push_back((elapsed, power));
+        while self.smooth.front().is_some_and(|(t, _)| *t <= elapsed - self.smooth_window as f64) {
+            self.smooth.pop_front();
+        }
+        let sum: u64 = self.smooth.iter().map(|(_, p)| *p as u64).sum();
+        (sum as f64 / self.smooth.len() as f64).round() as u32
+    }
+
+    /// Integer mean of all power values seen so far
+    fn avg_power(&self) -> u32 {
+        if self.power_count == 0 {
+            return 0;
+        }
+        (self.power_sum / self.power_count) as u32
+    }
+
+    /// Largest power value seen so far
+    fn max_power(&self) -> u32 {
+        self.power_max
+    }
+
+    /// Integrate power over `dt` seconds into the mechanical work done
+    fn add_work(&mut self, power: u32, dt: f64) {
+        self.work_joules += power as f64 * dt;
+    }
+
+    /// Account for `dt` seconds at `power` in the W' balance (Skiba): the work above the
+    /// critical power is spent, recovering exponentially with a time constant which gets
+    /// shorter the further the power was below the critical power on average
+    fn add_wbal(&mut self, power: u32, dt: f64) {
+        let Some((cp, _)) = self.wprime else {
+            return;
+        };
+        let power = power as f64;
+        if power < cp {
+            self.below_cp += (cp - power) * dt;
+            self.below_cp_seconds += dt;
+        }
+        let below = if self.below_cp_seconds > 0.0 {
+            self.below_cp / self.below_cp_seconds
+        } else {
+            0.0
+        };
+        let tau = 546.0 * (-0.01 * below).exp() + 316.0;
+        self.wprime_spent = self.wprime_spent * (-dt / tau).exp() + (power - cp).max(0.0) * dt;
+    }
+
+    /// W' balance in joules, 0 without critical power and W'
+    fn wbal(&self) -> i32 {
+        self.wprime.map_or(0, |(_, wprime)| clamp_i32((wprime - self.wprime_spent).round()))
+    }
+
+    /// Mechanical work done so far in kJ
+    fn work_kj(&self) -> u32 {
+        (self.work_joules / 1000.0).round() as u32
+    }
+
+    /// Estimated energy expenditure in kcal after `seconds` of riding, with a human efficiency
+    /// of roughly 24% the kcal burned are about the same as the kJ of mechanical work. With
+    /// the rider's weight the resting metabolism (1 kcal per kg and hour) is added.
+    fn calories(&self, seconds: u32) -> u32 {
+        let resting = self.weight.map_or(0.0, |weight| weight * seconds as f64 / 3600.0);
+        (self.work_joules / 1000.0 + resting).round() as u32
+    }
+
+    /// Power per kg of the rider's weight, 0 without weight
+    fn wkg(&self, power: u32) -> f32 {
+        match self.weight {
+            Some(weight) if weight > 0.0 => (power as f64 / weight) as f32,
+            _ => 0.0,
+        }
+    }
+
+    /// Count the `dt` seconds up to a record as moving if its speed (m/s) is above the moving
+    /// threshold, records without speed count as moving while pedaling
+    fn add_moving(&mut self, speed: Option<f64>, cadence: Option<u32>, dt: f64) {
+        let moving = match (speed, cadence) {
+            (Some(speed), _) => speed * 3.6 > self.moving_threshold,
+            (None, Some(cadence)) => cadence > 0,
+            (None, None) => false,
+        };
+        if moving {
+            self.moving_seconds += dt;
+            if let Some(speed) = speed {
+                self.moving_distance += speed * dt;
+                self.speed_seconds += dt;
+            }
+        }
+        if let Some(speed) = speed {
+            self.speed_max = self.speed_max.max(speed);
+        }
+    }
+
+    /// Mean speed in m/s over the moving time, the stops below '--moving-threshold' don't
+    /// pull it down
+    fn avg_speed(&self) -> f64 {
+        if self.speed_seconds <= 0.0 {
+            return 0.0;
+        }
+        self.moving_distance / self.speed_seconds
+    }
+
+    /// Largest speed in m/s seen so far
+    fn max_speed(&self) -> f64 {
+        self.speed_max
+    }
+
+    /// Account for a record which carried an altitude, changes are only counted once they
+    /// exceed the dead band to not sum up sensor noise
+    fn add_altitude(&mut self, elapsed: f64, altitude: f64) {
+        // the oldest record kept is the last one at or before the start of the window
+        self.vam_altitudes.push_back((elapsed, altitude));
+        while self.vam_altitudes.get(1).is_some_and(|(t, _)| *t <= elapsed - self.vam_window) {
+            self.vam_altitudes.pop_front();
+        }
+
+        let reference = *self.elevation_ref.get_or_insert(altitude);
+        let delta = altitude - reference;
+        if delta.abs() < self.elevation_deadband {
+            return;
+        }
+        if delta > 0.0 {
+            self.ascent += delta;
+        } else {
+            self.descent -= delta;
+        }
+        self.elevation_ref = Some(altitude);
+    }
+
+    /// Meters climbed so far
+    fn total_ascent(&self) -> u32 {
+        self.ascent.round() as u32
+    }
+
+    /// Meters descended so far
+    fn total_descent(&self) -> u32 {
+        self.descent.round() as u32
+    }
+
+    /// Meters climbed per hour over the VAM window, 0 until the records span the window and
+    /// while not climbing
+    fn vam(&self) -> u32 {
+        let (first, last) = match (self.vam_altitudes.front(), self.vam_altitudes.back()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return 0,
+        };
+        let seconds = last.0 - first.0;
+        if seconds <= 0.0 || seconds < self.vam_window {
+            return 0;
+        }
+        clamp_u32(((last.1 - first.1) / seconds * 3600.0).round())
+    }
+
+    /// Seconds spent moving so far
+    fn moving_time(&self) -> u32 {
+        self.moving_seconds.round() as u32
+    }
+
+    /// Account for a record which carried a cadence value
+    fn add_cadence(&mut self, cadence: u32) {
+        self.cadence_max = self.cadence_max.max(cadence);
+        if cadence == 0 && self.cadence_exclude_zeros {
+            return;
+        }
+        self.cadence_sum += cadence as u64;
+        self.cadence_count += 1;
+    }
+
+    /// Integer mean of the cadence values seen so far
+    fn avg_cadence(&self) -> u32 {
+        if self.cadence_count == 0 {
+            return 0;
+        }
+        (self.cadence_sum / self.cadence_count) as u32
+    }
+
+    /// Largest cadence value seen so far
+    fn max_cadence(&self) -> u32 {
+        self.cadence_max
+    }
+
+    /// Account for a record which carried a heart rate, zeros are sensor dropouts
+    fn add_heartrate(&mut self, heartrate: u32) {
+        if heartrate == 0 {
+            return;
+        }
+        self.heartrate_sum += heartrate as u64;
+        self.heartrate_count += 1;
+        self.heartrate_max = self.heartrate_max.max(heartrate);
+    }
+
+    /// Integer mean of the valid heart rates seen so far
+    fn avg_heartrate(&self) -> u32 {
+        if self.heartrate_count == 0 {
+            return 0;
+        }
+        (self.heartrate_sum / self.heartrate_count) as u32
+    }
+
+    /// Largest valid heart rate seen so far
+    fn max_heartrate(&self) -> u32 {
+        self.heartrate_max
+    }
+
+    /// Normalized power, 0 until the first full rolling window is available
+    fn nrm_power(&self) -> u32 {
+        if self.np_count == 0 {
+            return 0;
+        }
+        (self.np_sum4 / self.np_count as f64).powf(0.25).round() as u32
+    }
+
+    /// NP in percent of the FTP, 0 if no FTP is known
+    fn intensity_factor(&self) -> u32 {
+        match self.ftp {
+            Some(ftp) if ftp > 0 => (self.nrm_power() as f64 / ftp as f64 * 100.0).round() as u32,
+            _ => 0,
+        }
+    }
+
+    /// Normalized power over the average heart rate times 100, 0 until both are known, heart
+    /// rate dropouts (0 bpm) don't count into the average
+    fn efficiency_factor(&self) -> u32 {
+        self.efficiency().map_or(0, |ef| (ef * 100.0).round() as u32)
+    }
+
+    /// Unscaled efficiency factor, None until both NP and heart rate are known
+    fn efficiency(&self) -> Option<f64> {
+        let np = self.nrm_power();
+        if np == 0 || self.heartrate_count == 0 {
+            return None;
+        }
+        let heartrate = self.heartrate_sum as f64 / self.heartrate_count as f64;
+        Some(np as f64 / heartrate)
+    }
+
+    /// NP in percent of the average power, 0 until both are known
+    fn variability_index(&self) -> u32 {
+        match self.avg_power() {
+            0 => 0,
+            avg => (self.nrm_power() as f64 / avg as f64 * 100.0).round() as u32,
+        }
+    }
+
+    /// Training stress score after `seconds` of riding, 0 if no FTP is known
+    fn tss(&self, seconds: u32) -> u32 {
+        let ftp = match self.ftp {
+            Some(ftp) if ftp > 0 => ftp as f64,
+            _ => return 0,
+        };
+        let np = self.nrm_power() as f64;
+        let intensity = np / ftp;
+        (seconds as f64 * np * intensity / (ftp * 3600.0) * 100.0).round() as u32
+    }
+}
+
+/// Offset into the activity in seconds, given as seconds or as 'MM:SS' or 'HH:MM:SS',
+/// minutes and seconds after the first field must be below 60
+pub fn parse_offset(s: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid time '{}', expected seconds, MM:SS or HH:MM:SS", s);
+    let parts = s
+        .trim()
+        .split(':')
+        .map(|part| part.parse::<u64>().map_err(|_| invalid()))
+        .collect::<Result<Vec<u64>, String>>()?;
+    if parts.len() > 3 || parts[1..].iter().any(|part| *part >= 60) {
+        return Err(invalid());
+    }
+    let seconds = parts.iter().fold(0, |total: u64, part| total.saturating_mul(60).saturating_add(*part));
+    u32::try_from(seconds).map_err(|_| format!("time '{}' is too large", s))
+}
+
+/// Zone boundaries as comma separated list, each value is the lower limit of the next zone,
+/// e.g. '150,200' gives zone 1 below 150, zone 2 from 150 and zone 3 from 200
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ZoneLimits(Vec<u32>);
+
+impl FromStr for ZoneLimits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ZoneLimits, String> {
+        let limits = s
+            .split(',')
+            .map(|v| v.trim().parse::<u32>().map_err(|_| format!("invalid zone limit '{}'", v)))
+            .collect::<Result<Vec<u32>, String>>()?;
+        if limits.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(format!("zone limits '{}' must be ascending", s));
+        }
+        Ok(ZoneLimits(limits))
+    }
+}
+
+impl ZoneLimits {
+    /// Lower limits of the 7 Coggan power zones 2 to 7 in percent of the FTP
+    const COGGAN: [u32; 6] = [56, 76, 91, 106, 121, 151];
+
+    /// Lower limits of the heart rate zones 2 to 5 in percent of the maximum heart rate
+    const MAX_HR: [u32; 4] = [60, 70, 80, 90];
+
+    fn coggan(ftp: u32) -> ZoneLimits {
+        let limits = ZoneLimits::COGGAN.iter().map(|p| (ftp as f64 * *p as f64 / 100.0).round() as u32);
+        ZoneLimits(limits.collect())
+    }
+
+    fn max_hr(max_hr: u32) -> ZoneLimits {
+        let limits = ZoneLimits::MAX_HR.iter().map(|p| (max_hr as f64 * *p as f64 / 100.0).round() as u32);
+        ZoneLimits(limits.collect())
+    }
+
+    /// Zone 1..=N the value falls in, 0 without any limits
+    fn zone(&self, value: u32) -> u32 {
+        if self.0.is_empty() {
+            return 0;
+        }
+        1 + self.0.iter().filter(|limit| value >= **limit).count() as u32
+    }
+}
+
+// options of the conversion into focus frames, the defaults are those of the command line,
+// no doc comment as it would replace the about text of the flattening command line
+#[derive(Debug, StructOpt)]
+pub struct ConvertOptions {
+    /// Skip the first seconds of the activity before starting playback, as seconds or
+    /// [HH:]MM:SS, e.g. '1:05:00'
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_offset))]
+    pub start: u32,
+
+    /// Stop playback once the activity passed this many seconds, as seconds or [HH:]MM:SS
+    #[structopt(long, parse(try_from_str = parse_offset))]
+    pub end: Option<u32>,
+
+    /// Interpolate the seconds missing in the recording to play back a steady 1 Hz stream
+    #[structopt(long)]
+    pub interpolate: bool,
+
+    /// Emit this many frames per recorded second, interpolating or decimating the records
+    #[structopt(long)]
+    pub rate: Option<f64>,
+
+    /// Offset in meters added to the recorded altitude
+    #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+    pub altitude_base: i32,
+
+    /// Factor applied to the speed in km/h to get the value TPV broadcasts
+    #[structopt(long, default_value = "275.0")]
+    pub speed_scale: f32,
+
+    /// Show the power as moving average over this many seconds, e.g. 3 for the 3s power,
+    /// the averages, maximum and NP still use the recorded power
+    #[structopt(long, default_value = "1")]
+    pub power_smooth: u32,
+
+    /// Power zones as comma separated watts, each the lower limit of the next zone, e.g.
+    /// '150,200,250' for four zones
+    #[structopt(long)]
+    pub zones: Option<ZoneLimits>,
+
+    /// Derive the 7 Coggan power zones from the FTP
+    #[structopt(long, conflicts_with = "zones")]
+    pub coggan_zones: bool,
+
+    /// Heart rate zones as comma separated bpm, each the lower limit of the next zone
+    #[structopt(long)]
+    pub hr_zones: Option<ZoneLimits>,
+
+    /// Maximum heart rate to derive 5 heart rate zones from (60/70/80/90% of the maximum)
+    #[structopt(long, conflicts_with = "hr-zones")]
+    pub max_hr: Option<u32>,
+
+    /// Altitude changes in meters below this are ignored as noise for the total ascent and descent
+    #[structopt(long, default_value = "1.0")]
+    pub elevation_deadband: f64,
+
+    /// Seconds the altitude gain is averaged over for the VAM
+    #[structopt(long, default_value = "60")]
+    pub vam_window: u32,
+
+    /// Speed in km/h above which the time counts as moving time
+    #[structopt(long, default_value = "1.0")]
+    pub moving_threshold: f64,
+
+    /// Rider's weight in kg for the watts per kg, also adds the resting metabolism to the calories
+    #[structopt(long)]
+    pub weight: Option<f64>,
+
+    /// Functional threshold power in watts to compute the TSS, taken from the FIT file if not given
+    #[structopt(long)]
+    pub ftp: Option<u32>,
+
+    /// Critical power in watts for the W' balance, needs '--wprime'
+    #[structopt(long, requires = "wprime")]
+    pub cp: Option<u32>,
+
+    /// W', the work in joules that can be done above the critical power, for the W' balance
+    #[structopt(long, requires = "cp")]
+    pub wprime: Option<u32>,
+
+    /// Leave out zero cadence (coasting) when computing the average cadence
+    #[structopt(long)]
+    pub cadence_exclude_zeros: bool,
+
+    /// FIT field (or developer field) with the drafting gain in percent
+    #[structopt(long, default_value = "draft")]
+    pub draft_field: String,
+
+    /// Leave out the time the recording was paused (timer stop to start events), the records
+    /// in between are dropped
+    #[structopt(long)]
+    pub skip_paused: bool,
+
+    /// Start the playback where the warmup ends: once the 30 s power first reaches
+    /// '--warmup-threshold' percent of the FTP, at the end of the first lap without an FTP
+    #[structopt(long)]
+    pub skip_warmup: bool,
+
+    /// Percent of the FTP the power has to reach to end the warmup for '--skip-warmup'
+    #[structopt(long, default_value = "75")]
+    pub warmup_threshold: u32,
+
+    /// Keep showing the previous value of a field missing in a record (e.g. a power or heart
+    /// rate dropout) instead of 0
+    #[structopt(long)]
+    pub hold_last: bool,
+
+    /// Seconds a value is held with '--hold-last' before falling back to 0
+    #[structopt(long, default_value = "5")]
+    pub hold_timeout: f64,
+
+    /// Wind loaded from '--wind-from'
+    #[structopt(skip)]
+    pub wind: wind::Wind,
+
+    /// Mapping loaded from '--map'
+    #[structopt(skip)]
+    pub field_map: mapping::FieldMap,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> ConvertOptions {
+        ConvertOptions::from_iter(["tpvfitplay"])
+    }
+}
+
+/// Alternate serialization format
+#[derive(Clone, Debug, Serialize)]
+pub struct FitDataMap {
+    pub kind: fitparser::profile::MesgNum,
+    pub timestamp: Option<i64>,
+    pub fields: BTreeMap<String, Value>,
+}
+
+impl FitDataMap {
+    fn new(record: FitDataRecord) -> Self {
+        let timestamp = record
+            .fields()
+            .iter()
+            .find(|f| f.name() == "timestamp")
+            .and_then(|f| value_timestamp(f.value()));
+
+        FitDataMap {
+            kind: record.kind(),
+            timestamp,
+            fields: record
+                .into_vec()
+                .into_iter()
+                .map(|f| (f.name().to_owned(), f.into_value()))
+                .collect(),
+        }
+    }
+
+    /// Convert all records of a parsed FIT file, records which only carry a 'timestamp_16'
+    /// get the full timestamp from the previous one
+    pub fn from_records(records: Vec<FitDataRecord>) -> Vec<FitDataMap> {
+        let mut last: Option<i64> = None;
+        records
+            .into_iter()
+            .map(FitDataMap::new)
+            .map(|mut fdm| {
+                if fdm.timestamp.is_none() {
+                    let low = fdm.fields.get("timestamp_16").and_then(value_u32);
+                    fdm.timestamp = last.zip(low).map(|(last, low)| expand_timestamp_16(last, low));
+                }
+                last = fdm.timestamp.or(last);
+                fdm
+            })
+            .collect()
+    }
+
+    /// Timestamp in seconds since the epoch including the fraction of the second the record
+    /// carries in 'fractional_timestamp' or 'timestamp_ms' (high-rate devices)
+    pub fn seconds(&self) -> Option<f64> {
+        let fraction = match (self.fields.get("fractional_timestamp"), self.fields.get("timestamp_ms")) {
+            (Some(seconds), _) => value_f64(seconds).unwrap_or(0.0),
+            (None, Some(ms)) => value_f64(ms).map_or(0.0, |ms| ms / 1000.0),
+            (None, None) => 0.0,
+        };
+        self.timestamp.map(|t| t as f64 + fraction)
+    }
+}
+
+/// Seconds between the Unix and the FIT epoch (1989-12-31 00:00:00 UTC)
+const FIT_EPOCH: i64 = 631_065_600;
+
+/// Full timestamp from the lower 16 bits of the FIT time, which roll over every 18 hours,
+/// relative to the previous full timestamp
+fn expand_timestamp_16(last: i64, low: u32) -> i64 {
+    let fit = last - FIT_EPOCH;
+    let delta = (low as i64 - (fit & 0xFFFF)) & 0xFFFF;
+    last + delta
+}
+
+/// Values of a single FIT record, None where the record doesn't carry the field
+#[derive(Clone, Debug, Default)]
+pub struct Sample {
+    /// seconds since the epoch, fractional for resampled records
+    pub timestamp: Option<f64>,
+    pub power: Option<u32>,
+    /// percent of the power from the left leg
+    pub balance: Option<u32>,
+    pub heartrate: Option<u32>,
+    pub cadence: Option<u32>,
+    /// meters
+    pub distance: Option<f64>,
+    /// m/s
+    pub speed: Option<f64>,
+    /// percent
+    pub grade: Option<f64>,
+    /// meters
+    pub altitude: Option<f64>,
+    /// degrees Celsius
+    pub temperature: Option<i32>,
+    /// percent
+    pub draft: Option<f64>,
+    /// m/s
+    pub wind_speed: Option<f64>,
+    /// degrees
+    pub wind_heading: Option<f64>,
+    /// latitude and longitude in degrees
+    pub position: Option<(f64, f64)>,
+}
+
+impl Sample {
+    fn new(fdm: &FitDataMap, opt: &ConvertOptions) -> Sample {
+        let mut sample = Sample {
+            timestamp: fdm.seconds(),
+            ..Sample::default()
+        };
+
+        let mut lat = None;
+        let mut long = None;
+        // older devices only record the plain fields, the enhanced ones are preferred
+        let mut speed = None;
+        let mut altitude = None;
+
+        for (name, value) in &fdm.fields {
+            log::trace!("{} {} = {}", fdm.kind, name, value);
+            match name.as_str() {
+                _ if *name == opt.draft_field => sample.draft = value_f64(value),
+                "power" => sample.power = value_u32(value),
+                "heart_rate" => sample.heartrate = value_u32(value),
+                "cadence" => sample.cadence = value_u32(value),
+                "distance" => sample.distance = value_f64(value),
+                "enhanced_speed" => sample.speed = value_f64(value),
+                "speed" => speed = value_f64(value),
+                "grade" => sample.grade = value_f64(value),
+                "enhanced_altitude" => sample.altitude = value_f64(value),
+                "altitude" => altitude = value_f64(value),
+                "left_right_balance" => sample.balance = value_u32(value).and_then(balance_left),
+                "temperature" => sample.temperature = value_f64(value).map(clamp_i32),
+                "wind_speed" => sample.wind_speed = value_f64(value),
+                "wind_heading" => sample.wind_heading = value_f64(value),
+                "position_lat" => lat = value_f64(value),
+                "position_long" => long = value_f64(value),
+                _ => {}
+            }
+        }
+        sample.speed = sample.speed.or(speed);
+        sample.altitude = sample.altitude.or(altitude);
+        if let (Some(lat), Some(long)) = (lat, long) {
+            sample.position = Some((semicircles_to_degrees(lat), semicircles_to_degrees(long)));
+        }
+        sample
+    }
+
+    /// Names of the focus fields this sample sets
+    fn focus_fields(&self) -> Vec<&'static str> {
+        [
+            ("power", self.power.is_some()),
+            ("powerBalance", self.balance.is_some()),
+            ("heartrate", self.heartrate.is_some()),
+            ("cadence", self.cadence.is_some()),
+            ("distance", self.distance.is_some()),
+            ("speed", self.speed.is_some()),
+            ("slope", self.grade.is_some()),
+            ("height", self.altitude.is_some()),
+            ("temperature", self.temperature.is_some()),
+            ("draft", self.draft.is_some()),
+            ("windSpeed", self.wind_speed.is_some()),
+            ("windAngle", self.wind_heading.is_some()),
+            ("lat/long", self.position.is_some()),
+        ]
+        .into_iter()
+        .filter(|(_, set)| *set)
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    /// Sample at `timestamp` between this and the `next` sample, `weight` goes from 0 (this)
+    /// to 1 (next), fields missing in one of both are left out
+    fn lerp(&self, next: &Sample, timestamp: f64, weight: f64) -> Sample {
+        let float = |a: Option<f64>, b: Option<f64>| Some(a? + (b? - a?) * weight);
+        let int = |a: Option<u32>, b: Option<u32>| {
+            float(a.map(f64::from), b.map(f64::from)).map(|v| clamp_u32(v.round()))
+        };
+
+        Sample {
+            timestamp: Some(timestamp),
+            power: int(self.power, next.power),
+            balance: int(self.balance, next.balance),
+            heartrate: int(self.heartrate, next.heartrate),
+            cadence: int(self.cadence, next.cadence),
+            distance: float(self.distance, next.distance),
+            speed: float(self.speed, next.speed),
+            grade: float(self.grade, next.grade),
+            altitude: float(self.altitude, next.altitude),
+            temperature: float(self.temperature.map(f64::from), next.temperature.map(f64::from))
+                .map(|t| clamp_i32(t.round())),
+            draft: float(self.draft, next.draft),
+            wind_speed: float(self.wind_speed, next.wind_speed),
+            // headings don't interpolate across north
+            wind_heading: self.wind_heading,
+            position: match (self.position, next.position) {
+                (Some(a), Some(b)) => Some((
+                    a.0 + (b.0 - a.0) * weight,
+                    a.1 + (b.1 - a.1) * weight,
+                )),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Percent left of a FIT power balance, the low 7 bits are the percentage of the right side
+/// if the high bit is set, without it the side is unknown and None is returned
+fn balance_left(raw: u32) -> Option<u32> {
+    let percent = raw & 0x7f;
+    if raw & 0x80 == 0 || percent > 100 {
+        return None;
+    }
+    Some(100 - percent)
+}
+
+/// Timestamp value in seconds since the epoch
+pub fn value_timestamp(value: &Value) -> Option<i64> {
+    match value {
+        Value::Timestamp(t) => Some(t.timestamp()),
+        _ => None,
+    }
+}
+
+/// Boundaries and totals of a lap as recorded in the FIT 'Lap' messages
+#[derive(Clone, Debug)]
+pub struct Lap {
+    pub start: i64,
+    pub end: i64,
+    /// seconds
+    pub elapsed: Option<f64>,
+    /// meters
+    pub distance: Option<f64>,
+    pub calories: Option<u32>,
+}
+
+/// All laps of the parsed data in recording order
+pub fn laps(data: &[FitDataMap]) -> Vec<Lap> {
+    data.iter()
+        .filter(|fdm| fdm.kind == MesgNum::Lap)
+        .filter_map(|fdm| {
+            let end = fdm.timestamp?;
+            let start = fdm.fields.get("start_time").and_then(value_timestamp).unwrap_or(end);
+            Some(Lap {
+                start,
+                end,
+                elapsed: fdm.fields.get("total_elapsed_time").and_then(value_f64),
+                distance: fdm.fields.get("total_distance").and_then(value_f64),
+                calories: fdm.fields.get("total_calories").and_then(value_u32),
+            })
+        })
+        .collect()
+}
+
+/// Sport of the first session, e.g. 'cycling'
+pub fn sport(data: &[FitDataMap]) -> Option<String> {
+    data.iter()
+        .filter(|fdm| fdm.kind == MesgNum::Session)
+        .find_map(|fdm| match fdm.fields.get("sport") {
+            Some(Value::String(sport)) => Some(sport.clone()),
+            _ => None,
+        })
+}
+
+/// Distance in meters of all sessions (several for merged files), the distance of the last
+/// record without sessions
+fn total_distance(data: &[FitDataMap]) -> Option<f64> {
+    let sessions: Vec<f64> = data
+        .iter()
+        .filter(|fdm| fdm.kind == MesgNum::Session)
+        .filter_map(|fdm| fdm.fields.get("total_distance").and_then(value_f64))
+        .collect();
+    (!sessions.is_empty())
+        .then(|| sessions.iter().sum())
+        .or_else(|| {
+            data.iter()
+                .rev()
+                .filter(|fdm| fdm.kind == MesgNum::Record)
+                .find_map(|fdm| fdm.fields.get("distance").and_then(value_f64))
+        })
+}
+
+/// FTP recorded in the 'ZonesTarget' or 'UserProfile' message
+pub fn recorded_ftp(data: &[FitDataMap]) -> Option<u32> {
+    data.iter()
+        .filter(|fdm| fdm.kind == MesgNum::ZonesTarget || fdm.kind == MesgNum::UserProfile)
+        .filter_map(|fdm| fdm.fields.get("functional_threshold_power").and_then(value_u32))
+        .find(|ftp| *ftp > 0)
+}
+
+/// FTP for the TSS, '--ftp' takes precedence over the FTP recorded in the file
+fn ftp(data: &[FitDataMap], opt: &ConvertOptions) -> Option<u32> {
+    opt.ftp.or_else(|| recorded_ftp(data))
+}
+
+/// FIT stores positions as semicircles, 2^31 semicircles are 180 degrees
+fn semicircles_to_degrees(semicircles: f64) -> f64 {
+    semicircles * 180.0 / 2f64.powi(31)
+}
+
+/// All records of the parsed data as samples, decoded by the built-in and the '--map' mapping
+pub fn samples<'a>(data: &'a [FitDataMap], opt: &'a ConvertOptions) -> impl Iterator<Item = Sample> + 'a {
+    let mut fallback = Fallback::default();
+    let pauses = if opt.skip_paused { pauses(data) } else { Vec::new() };
+    let paused = move |fdm: &FitDataMap| {
+        fdm.timestamp
+            .is_some_and(|t| pauses.iter().any(|(stop, start)| t as f64 > *stop && (t as f64) < *start))
+    };
+
+    data.iter()
+        .filter(|fdm| fdm.kind == MesgNum::Record)
+        .filter(move |fdm| !paused(fdm))
+        .map(move |fdm| {
+            let mut sample = Sample::new(fdm, opt);
+            opt.field_map.apply(fdm, &mut sample);
+            fallback.fill(&mut sample);
+            sample
+        })
+}
+
+/// Distance in meters the rider has to move before the grade is computed again, shorter
+/// distances turn the altitude noise into wild grades
+const GRADE_DISTANCE: f64 = 10.0;
+
+/// Seconds the speed is averaged over, most devices record the timestamps in whole seconds
+const SPEED_WINDOW: f64 = 3.0;
+
+/// Derives fields the records don't carry from the previous records
+#[derive(Debug, Default)]
+struct Fallback {
+    /// distance and altitude the grade is computed from
+    grade_from: Option<(f64, f64)>,
+    grade: Option<f64>,
+    /// time and distance of the records within the speed window
+    speed_from: VecDeque<(f64, f64)>,
+    speed: Option<f64>,
+}
+
+impl Fallback {
+    /// Fill in the missing fields of the next sample in recording order
+    fn fill(&mut self, sample: &mut Sample) {
+        if let (Some(distance), Some(altitude)) = (sample.distance, sample.altitude) {
+            let (from_distance, from_altitude) = *self.grade_from.get_or_insert((distance, altitude));
+            let delta = distance - from_distance;
+            // standing still holds the previous grade
+            if delta >= GRADE_DISTANCE {
+                self.grade = Some((altitude - from_altitude) / delta * 100.0);
+                self.grade_from = Some((distance, altitude));
+            }
+        }
+        if sample.grade.is_none() {
+            sample.grade = self.grade;
+        }
+
+        if let Some(distance) = sample.distance {
+            // records without timestamp are one second apart
+            let time = match (sample.timestamp, self.speed_from.back()) {
+                (Some(t), _) => t,
+                (None, Some((prev, _))) => prev + 1.0,
+                (None, None) => 0.0,
+            };
+            // only the first record at a time is used, further ones hold the speed
+            if self.speed_from.back().is_none_or(|(t, _)| time > *t) {
+                self.speed_from.push_back((time, distance));
+                while self.speed_from.get(1).is_some_and(|(t, _)| *t <= time - SPEED_WINDOW) {
+                    self.speed_from.pop_front();
+                }
+                if let Some((from_time, from_distance)) = self.speed_from.front() {
+                    if time > *from_time {
+                        self.speed = Some((distance - from_distance).max(0.0) / (time - from_time));
+                    }
+                }
+            }
+        }
+        if sample.speed.is_none() {
+            sample.speed = self.speed;
+        }
+    }
+}
+
+/// Samples every `step` seconds between the first and the last record, interpolated
+/// linearly between the records around them. Records without timestamp can't be placed and
+/// are dropped, records at the same time are reduced to the last one and the distance never
+/// decreases. The step may be shorter than a second for high-rate recordings.
+fn resample(samples: Vec<Sample>, step: f64) -> Vec<Sample> {
+    let mut records: Vec<Sample> = samples.into_iter().filter(|s| s.timestamp.is_some()).collect();
+    records.dedup_by(|next, prev| {
+        if next.timestamp <= prev.timestamp {
+            *prev = next.clone();
+            return true;
+        }
+        false
+    });
+
+    let (first, last) = match (records.first(), records.last()) {
+        (Some(first), Some(last)) => (first.timestamp.unwrap_or(0.0), last.timestamp.unwrap_or(0.0)),
+        _ => return records,
+    };
+
+    let mut result: Vec<Sample> = Vec::new();
+    let mut index = 0;
+    for n in 0.. {
+        let t = first + n as f64 * step;
+        if t > last {
+            break;
+        }
+        while records.get(index + 1).is_some_and(|r| r.timestamp <= Some(t)) {
+            index += 1;
+        }
+        let prev = &records[index];
+        let a = prev.timestamp.unwrap_or(t);
+        let sample = match records.get(index + 1) {
+            Some(next) if t > a => {
+                let b = next.timestamp.unwrap_or(t);
+                prev.lerp(next, t, (t - a) / (b - a))
+            }
+            _ => prev.clone(),
+        };
+        result.push(sample);
+    }
+
+    let mut distance: f64 = 0.0;
+    for sample in result.iter_mut() {
+        if let Some(d) = sample.distance.as_mut() {
+            distance = distance.max(*d);
+            *d = distance;
+        }
+    }
+    result
+}
+
+/// Paused intervals as seconds since the epoch, from a timer stop event to the next start
+fn pauses(data: &[FitDataMap]) -> Vec<(f64, f64)> {
+    let mut pauses = Vec::new();
+    let mut stopped: Option<i64> = None;
+    for fdm in data.iter().filter(|fdm| fdm.kind == MesgNum::Event) {
+        let field = |name: &str| fdm.fields.get(name).map(|v| v.to_string());
+        // named or numeric enums, 'timer' is event 0
+        if !matches!(field("event").as_deref(), Some("timer" | "0")) {
+            continue;
+        }
+        match field("event_type").as_deref() {
+            Some("start" | "0") => {
+                if let (Some(stop), Some(start)) = (stopped.take(), fdm.timestamp) {
+                    pauses.push((stop as f64, start as f64));
+                }
+            }
+            Some("stop" | "stop_all" | "stop_disable" | "stop_disable_all" | "1" | "4" | "8" | "9") => {
+                stopped = stopped.or(fdm.timestamp);
+            }
+            _ => {}
+        }
+    }
+    pauses
+}
+
+/// Elapsed time of the records relative to the first one, with '--skip-paused' the
+/// time the timer was stopped doesn't count
+#[derive(Debug, Default)]
+pub struct Clock {
+    first_timestamp: Option<f64>,
+    prev_time: Option<f64>,
+    pauses: Vec<(f64, f64)>,
+}
+
+impl Clock {
+    pub fn new(data: &[FitDataMap], opt: &ConvertOptions) -> Clock {
+        Clock {
+            pauses: if opt.skip_paused { pauses(data) } else { Vec::new() },
+            ..Clock::default()
+        }
+    }
+
+    /// Elapsed seconds at the given timestamp plus the seconds passed since the previous
+    /// call, counts up by one second if there is no timestamp
+    pub fn tick(&mut self, timestamp: Option<f64>) -> (f64, f64) {
+        let time = match timestamp {
+            Some(t) => {
+                let first = *self.first_timestamp.get_or_insert(t);
+                let paused: f64 = self
+                    .pauses
+                    .iter()
+                    .map(|(stop, start)| (start.min(t) - stop.max(first)).max(0.0))
+                    .sum();
+                (t - first - paused).max(0.0)
+            }
+            None => self.prev_time.map_or(0.0, |t| t + 1.0),
+        };
+        let dt = self.prev_time.map_or(0.0, |t| (time - t).max(0.0));
+        self.prev_time = Some(time);
+        (time, dt)
+    }
+}
+
+/// Speed from m/s in the TPV display unit: TPV broadcasts the speed as an integer, which is
+/// km/h multiplied by the speed scale
+fn tpv_speed(opt: &ConvertOptions, speed: f64) -> u32 {
+    clamp_u32(speed * 3.6 * opt.speed_scale as f64)
+}
+
+/// Altitude in meters including '--altitude-base'
+pub fn height(opt: &ConvertOptions, altitude: f64) -> i32 {
+    opt.altitude_base.saturating_add(clamp_i32(altitude))
+}
+
+/// Previous frame for '--hold-last', with the elapsed seconds each field was recorded last
+#[derive(Debug, Default)]
+struct Hold {
+    prev: Option<Focus>,
+    seen: BTreeMap<&'static str, f64>,
+}
+
+impl Hold {
+    /// Copy the fields the sample doesn't carry from the previous frame, until they weren't
+    /// recorded for longer than `timeout` seconds
+    fn apply(&mut self, sample: &Sample, has_wind: bool, focus: &mut Focus, timeout: f64) {
+        // nothing was seen before the first frame, so it holds nothing
+        let Hold { prev, seen } = self;
+        let last = prev.take().unwrap_or_default();
+        let elapsed = focus.elapsed;
+        let mut held = |name: &'static str, recorded: bool| {
+            if recorded {
+                seen.insert(name, elapsed);
+                return false;
+            }
+            seen.get(name).is_some_and(|at| elapsed - at <= timeout)
+        };
+        if held("power", sample.power.is_some()) {
+            focus.power = last.power;
+            focus.powerZone = last.powerZone;
+        }
+        if held("powerBalance", sample.balance.is_some()) {
+            focus.powerBalance = last.powerBalance;
+        }
+        if held("heartrate", sample.heartrate.is_some()) {
+            focus.heartrate = last.heartrate;
+        }
+        if held("cadence", sample.cadence.is_some()) {
+            focus.cadence = last.cadence;
+        }
+        if held("distance", sample.distance.is_some()) {
+            focus.distance = last.distance;
+            focus.eventDistanceDone = last.eventDistanceDone;
+        }
+        if held("speed", sample.speed.is_some()) {
+            focus.speed = last.speed;
+        }
+        if held("slope", sample.grade.is_some()) {
+            focus.slope = last.slope;
+        }
+        if held("height", sample.altitude.is_some()) {
+            focus.height = last.height;
+        }
+        if held("temperature", sample.temperature.is_some()) {
+            focus.temperature = last.temperature;
+        }
+        if held("draft", sample.draft.is_some()) {
+            focus.draft = last.draft;
+        }
+        if held("wind", has_wind) {
+            focus.windSpeed = last.windSpeed;
+            focus.windAngle = last.windAngle;
+        }
+        if held("position", sample.position.is_some()) {
+            focus.lat = last.lat;
+            focus.long = last.long;
+            focus.hasPosition = last.hasPosition;
+        }
+        *prev = Some(focus.clone());
+    }
+}
+
+/// Turns samples into focus frames while keeping track of the running metrics
+struct Converter<'a> {
+    opt: &'a ConvertOptions,
+    base: Focus,
+    clock: Clock,
+    metrics: Metrics,
+    /// timestamps the laps ended at
+    lap_ends: Vec<i64>,
+    power_zones: ZoneLimits,
+    hr_zones: ZoneLimits,
+    /// zone of the last valid heart rate, held over dropouts
+    hr_zone: u32,
+    hold: Hold,
+    warned_below_zero: bool,
+}
+
+impl<'a> Converter<'a> {
+    fn new(opt: &'a ConvertOptions, rider: &Rider, data: &[FitDataMap]) -> Converter<'a> {
+        let mut base = Focus::new();
+        rider.apply(&mut base);
+
+        let ftp = ftp(data, opt);
+        let lap_ends: Vec<i64> = laps(data).iter().map(|lap| lap.end).collect();
+        base.eventLapsTotal = lap_ends.len() as u32;
+        base.eventDistanceTotal = total_distance(data).unwrap_or(0.0) as u32;
+
+        let power_zones = match (&opt.zones, ftp) {
+            (Some(zones), _) => zones.clone(),
+            (None, Some(ftp)) if opt.coggan_zones => ZoneLimits::coggan(ftp),
+            _ => ZoneLimits::default(),
+        };
+        let hr_zones = match (&opt.hr_zones, opt.max_hr) {
+            (Some(zones), _) => zones.clone(),
+            (None, Some(max_hr)) => ZoneLimits::max_hr(max_hr),
+            _ => ZoneLimits::default(),
+        };
+
+        Converter {
+            opt,
+            base,
+            clock: Clock::new(data, opt),
+            metrics: Metrics::new(opt, ftp),
+            lap_ends,
+            power_zones,
+            hr_zones,
+            hr_zone: 0,
+            hold: Hold::default(),
+            warned_below_zero: false,
+        }
+    }
+
+    /// Frame for the next sample in playback order
+    fn convert(&mut self, sample: &Sample) -> Focus {
+        let opt = self.opt;
+        let metrics = &mut self.metrics;
+        let mut focus = self.base.clone();
+
+        let (elapsed, dt) = self.clock.tick(sample.timestamp);
+        focus.time = elapsed as u32;
+        focus.timeMs = (elapsed * 1000.0).round() as u32;
+        focus.elapsed = elapsed;
+        focus.timestamp = sample.timestamp;
+
+        if let Some(timestamp) = sample.timestamp {
+            focus.eventLapsDone = self.lap_ends.iter().filter(|end| **end as f64 <= timestamp).count() as i32;
+        }
+
+        if let Some(power) = sample.power {
+            focus.power = metrics.smooth_power(elapsed, power);
+            focus.powerZone = self.power_zones.zone(focus.power);
+            metrics.add_power(elapsed, power);
+            metrics.add_work(power, dt);
+            metrics.add_wbal(power, dt);
+        }
+        if let Some(balance) = sample.balance {
+            focus.powerBalance = balance;
+        }
+        if let Some(heartrate) = sample.heartrate {
+            focus.heartrate = heartrate;
+            metrics.add_heartrate(heartrate);
+            if heartrate > 0 {
+                self.hr_zone = self.hr_zones.zone(heartrate);
+            }
+        }
+        focus.hrZone = self.hr_zone;
+        if let Some(cadence) = sample.cadence {
+            focus.cadence = cadence;
+            metrics.add_cadence(cadence);
+        }
+        if let Some(distance) = sample.distance {
+            focus.distance = clamp_u32(distance);
+            focus.eventDistanceDone = clamp_u32(distance);
+        }
+        if let Some(speed) = sample.speed {
+            focus.speed = tpv_speed(opt, speed);
+        }
+        if let Some(grade) = sample.grade {
+            focus.slope = clamp_i32(grade);
+        }
+        if let Some(altitude) = sample.altitude {
+            let height = height(opt, altitude);
+            if height < 0 && !self.warned_below_zero {
+                log::warn!("altitude below zero, clamping height to 0");
+                self.warned_below_zero = true;
+            }
+            focus.height = height.max(0) as u32;
+            metrics.add_altitude(elapsed, altitude);
+        }
+        if let Some(temperature) = sample.temperature {
+            focus.temperature = temperature;
+        }
+        if let Some(draft) = sample.draft {
+            focus.draft = draft.clamp(0.0, 100.0).round() as u32;
+        }
+        let recorded_wind = match (sample.wind_speed, sample.wind_heading) {
+            (Some(speed), Some(heading)) => Some((speed * 3.6, heading)),
+            _ => None,
+        };
+        // the simulated wind replaces the recorded one
+        let wind = opt.wind.at(elapsed).or(recorded_wind);
+        if let Some((speed, angle)) = wind {
+            focus.windSpeed = clamp_u32(speed.round());
+            focus.windAngle = angle.rem_euclid(360.0).round() as u32 % 360;
+        }
+        if let Some((lat, long)) = sample.position {
+            focus.lat = lat;
+            focus.long = long;
+            focus.hasPosition = true;
+        }
+        if opt.hold_last {
+            self.hold.apply(sample, wind.is_some(), &mut focus, opt.hold_timeout);
+        }
+
+        metrics.add_moving(sample.speed, sample.cadence, dt);
+        focus.movingTime = metrics.moving_time();
+        focus.avgSpeed = tpv_speed(opt, metrics.avg_speed());
+        focus.maxSpeed = tpv_speed(opt, metrics.max_speed());
+
+        focus.avgPower = metrics.avg_power();
+        focus.maxPower = metrics.max_power();
+        focus.nrmPower = metrics.nrm_power();
+        focus.avgCadence = metrics.avg_cadence();
+        focus.maxCadence = metrics.max_cadence();
+        focus.avgHeartrate = metrics.avg_heartrate();
+        focus.maxHeartrate = metrics.max_heartrate();
+        focus.tss = metrics.tss(focus.time);
+        focus.intensityFactor = metrics.intensity_factor();
+        focus.variabilityIndex = metrics.variability_index();
+        focus.efficiencyFactor = metrics.efficiency_factor();
+        focus.calories = metrics.calories(focus.time);
+        focus.workKj = metrics.work_kj();
+        focus.wBal = metrics.wbal();
+        focus.wkg = metrics.wkg(focus.power);
+        focus.totalAscent = metrics.total_ascent();
+        focus.totalDescent = metrics.total_descent();
+        focus.vam = metrics.vam();
+
+        if log::log_enabled!(log::Level::Debug) {
+            log::debug!("time {}: set {}", focus.time, sample.focus_fields().join(", "));
+        }
+        focus
+    }
+}
+
+/// Seconds between the resampled frames, None to play the records as recorded
+pub fn resample_step(opt: &ConvertOptions) -> Option<f64> {
+    match opt.rate {
+        Some(rate) => Some(1.0 / rate),
+        None if opt.interpolate => Some(1.0),
+        None => None,
+    }
+}
+
+/// Seconds the power is averaged over to detect the end of the warmup
+const WARMUP_WINDOW: f64 = 30.0;
+
+/// End of the warmup detected for '--skip-warmup'
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Warmup {
+    /// elapsed seconds the 30 s power first reached the threshold
+    Power(u32),
+    /// elapsed seconds the first lap ended
+    Lap(u32),
+}
+
+impl Warmup {
+    pub fn end(&self) -> u32 {
+        match *self {
+            Warmup::Power(end) | Warmup::Lap(end) => end,
+        }
+    }
+}
+
+/// End of the warmup by the power if there's an FTP, else (or if the power never reaches the
+/// threshold) by the first of several laps, None if neither applies
+pub fn warmup(data: &[FitDataMap], opt: &ConvertOptions) -> Option<Warmup> {
+    let elapsed = || {
+        let mut clock = Clock::new(data, opt);
+        samples(data, opt).map(move |sample| (clock.tick(sample.timestamp).0, sample))
+    };
+
+    let by_power = ftp(data, opt).filter(|ftp| *ftp > 0).and_then(|ftp| {
+        let threshold = ftp as f64 * opt.warmup_threshold as f64 / 100.0;
+        let mut window: VecDeque<(f64, u32)> = VecDeque::new();
+        elapsed().find_map(|(time, sample)| {
+            window.push_back((time, sample.power?));
+            while window.front().is_some_and(|(t, _)| *t <= time - WARMUP_WINDOW) {
+                window.pop_front();
+            }
+            let mean = window.iter().map(|(_, p)| *p as f64).sum::<f64>() / window.len() as f64;
+            (time >= WARMUP_WINDOW && mean >= threshold).then_some(Warmup::Power(time as u32))
+        })
+    });
+
+    by_power.or_else(|| {
+        let laps = laps(data);
+        let first = laps.first().filter(|_| laps.len() > 1)?;
+        elapsed()
+            .find(|(_, sample)| sample.timestamp.is_some_and(|t| t >= first.end as f64))
+            .map(|(time, _)| Warmup::Lap(time as u32))
+    })
+}
+
+/// Elapsed seconds the output begins at, '--start' or the end of the warmup with
+/// '--skip-warmup' if that is later
+pub fn start_offset(data: &[FitDataMap], opt: &ConvertOptions) -> u32 {
+    match opt.skip_warmup {
+        true => warmup(data, opt).map_or(opt.start, |warmup| warmup.end().max(opt.start)),
+        false => opt.start,
+    }
+}
+
+/// Focus frames of all records between '--start' and '--end', with fresh metrics
+pub fn frames<'a>(
+    data: &'a [FitDataMap],
+    opt: &'a ConvertOptions,
+    rider: &Rider,
+) -> impl Iterator<Item = Focus> + 'a {
+    let mut converter = Converter::new(opt, rider, data);
+    let start = start_offset(data, opt);
+
+    let mut samples: Vec<Sample> = samples(data, opt).collect();
+    if let Some(step) = resample_step(opt) {
+        samples = resample(samples, step);
+    }
+
+    samples
+        .into_iter()
+        .map(move |sample| converter.convert(&sample))
+        .take_while(move |focus| opt.end.is_none_or(|end| focus.time <= end))
+        // fast forward to the start offset, the metrics still take the records into account
+        .filter(move |focus| focus.time >= start)
+}
+
+/// Efficiency factor (NP over the average heart rate) of the samples as the playback computes
+/// it, e.g. of a part of the ride, None without power or heart rate
+pub fn efficiency_factor(samples: &[Sample]) -> Option<f64> {
+    let mut metrics = Metrics::default();
+    let mut clock = Clock::default();
+    for sample in samples {
+        let (elapsed, _) = clock.tick(sample.timestamp);
+        if let Some(power) = sample.power {
+            metrics.add_power(elapsed, power);
+        }
+        if let Some(heartrate) = sample.heartrate {
+            metrics.add_heartrate(heartrate);
+        }
+    }
+    metrics.efficiency()
+}
+
+/// Focus frames of all records of a parsed FIT file, as played back for a rider without
+/// identity
+pub fn records_to_focus(records: &[FitDataRecord], opts: &ConvertOptions) -> Vec<Focus> {
+    let data = FitDataMap::from_records(records.to_vec());
+    frames(&data, opts, &Rider::default()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use fitparser::FitDataField;
+
+    #[test]
+    fn balance_without_side_is_unknown() {
+        assert_eq!(balance_left(0), None);
+        assert_eq!(balance_left(45), None);
+        assert_eq!(balance_left(0x80 | 45), Some(55));
+        assert_eq!(balance_left(0x80 | 120), None);
+    }
+
+    #[test]
+    fn float_values_round_to_u32() {
+        assert_eq!(value_u32(&Value::Float32(249.6)), Some(250));
+        assert_eq!(value_u32(&Value::Float64(249.4)), Some(249));
+        assert_eq!(value_u32(&Value::Float32(f32::NAN)), None);
+        assert_eq!(value_u32(&Value::Float64(-1.0)), Some(0));
+        assert_eq!(value_u32(&Value::Float64(1e12)), Some(u32::MAX));
+        assert_eq!(value_u32(&Value::SInt16(-2)), Some(0));
+    }
+
+    fn record(fields: &[(&str, Value)]) -> FitDataMap {
+        FitDataMap {
+            kind: fitparser::profile::MesgNum::Record,
+            timestamp: None,
+            fields: fields.iter().map(|(name, value)| (name.to_string(), value.clone())).collect(),
+        }
+    }
+
+    #[test]
+    fn legacy_speed_is_used_without_enhanced_speed() {
+        let opt = ConvertOptions::default();
+        let sample = Sample::new(&record(&[("speed", Value::Float64(5.0))]), &opt);
+        assert_eq!(sample.speed, Some(5.0));
+
+        let both = [("enhanced_speed", Value::Float64(7.5)), ("speed", Value::Float64(5.0))];
+        assert_eq!(Sample::new(&record(&both), &opt).speed, Some(7.5));
+    }
+
+    /// One record per second with the power of each second
+    fn ride(power: &[u32]) -> Vec<FitDataRecord> {
+        let start = chrono::Local.timestamp_opt(1_700_000_000, 0).unwrap();
+        power
+            .iter()
+            .enumerate()
+            .map(|(second, power)| {
+                let mut record = FitDataRecord::new(MesgNum::Record);
+                let timestamp = Value::Timestamp(start + chrono::Duration::seconds(second as i64));
+                record.push(FitDataField::new("timestamp".into(), 253, None, timestamp, "s".into()));
+                let power = Value::UInt16(*power as u16);
+                record.push(FitDataField::new("power".into(), 7, None, power, "watts".into()));
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn metrics_of_constant_power() {
+        let mut metrics = Metrics::new(&ConvertOptions::default(), Some(200));
+        for second in 0..29 {
+            metrics.add_power(second as f64, 200);
+        }
+        assert_eq!(metrics.avg_power(), 200);
+        assert_eq!(metrics.nrm_power(), 0, "no full window yet");
+
+        metrics.add_power(29.0, 200);
+        assert_eq!(metrics.nrm_power(), 200);
+        assert_eq!(metrics.variability_index(), 100);
+        assert_eq!(metrics.intensity_factor(), 100);
+    }
+
+    #[test]
+    fn converter_averages_constant_power() {
+        let opt = ConvertOptions::default();
+        let data = FitDataMap::from_records(ride(&[200; 60]));
+        let mut converter = Converter::new(&opt, &Rider::default(), &data);
+        let last = data
+            .iter()
+            .map(|fdm| converter.convert(&Sample::new(fdm, &opt)))
+            .last()
+            .unwrap();
+        assert_eq!(last.time, 59);
+        assert_eq!(last.avgPower, 200);
+        assert_eq!(last.maxPower, 200);
+        assert_eq!(last.nrmPower, 200);
+    }
+
+    #[test]
+    fn records_to_focus_metric_math() {
+        // half a minute at 100 W, then at 300 W, the 31 rolling windows ramp from 100 to 300
+        let power: Vec<u32> = [100; 30].iter().chain(&[300; 30]).copied().collect();
+        let frames = records_to_focus(&ride(&power), &ConvertOptions::default());
+        assert_eq!(frames.len(), 60);
+
+        let last = frames.last().unwrap();
+        assert_eq!(last.power, 300);
+        assert_eq!(last.avgPower, 200);
+        assert_eq!(last.maxPower, 300);
+        assert_eq!(last.nrmPower, 223);
+        assert_eq!(frames[28].nrmPower, 0);
+        assert_eq!(frames[29].nrmPower, 100);
+    }
+
+    #[test]
+    fn nrm_power_does_not_drift_with_the_rate() {
+        let records = fitparser::from_bytes(include_bytes!("../tests/ride_1.fit")).unwrap();
+        let nrm_power = |rate: &str| {
+            let opt = ConvertOptions::from_iter(["tpvfitplay", "--rate", rate]);
+            records_to_focus(&records, &opt).last().unwrap().nrmPower
+        };
+        assert_eq!(nrm_power("1"), 172);
+        assert_eq!(nrm_power("4"), 172);
+    }
+
+    #[test]
+    fn records_to_focus_golden() {
+        let records = fitparser::from_bytes(include_bytes!("../tests/ride_1.fit")).unwrap();
+        let frames = records_to_focus(&records, &ConvertOptions::default());
+        let golden: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/ride_1_last.json")).unwrap();
+        assert_eq!(frames.len(), 4498);
+        assert_eq!(serde_json::to_value(frames.last().unwrap()).unwrap(), golden);
+    }
+}
@@ -1,119 +1,408 @@
 //! Read one or more FIT files and dump their contents as JSON TPV 'focus.json'
-use fitparser::de::{from_reader_with_options, DecodeOption};
+use fitparser::de::DecodeOption;
 use fitparser::profile::MesgNum;
+use fitparser::Value;
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, TimeZone};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashSet};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::Write;
+use std::str::FromStr;
 use std::{io, thread, time};
-use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
-#[derive(Debug, Serialize, Clone)]
-#[allow(dead_code, non_snake_case)]
-pub struct Focus {
-    name: String,
-    country: String,
-    team: String,
-    teamCode: String,
-    power: u32,
-    avgPower: u32,
-    nrmPower: u32,
-    maxPower: u32,
-    cadence: u32,
-    avgCadence: u32,
-    maxCadence: u32,
-    heartrate: u32,
-    avgHeartrate: u32,
-    maxHeartrate: u32,
-    time: u32,
-    distance: u32,
-    height: u32,
-    speed: u32,
-    tss: u32,
-    calories: u32,
-    draft: u32,
-    windSpeed: u32,
-    windAngle: u32,
-    slope: i32,
-    eventLapsTotal: u32,
-    eventLapsDone: i32,
-    eventDistanceTotal: u32,
-    eventDistanceDone: u32,
-    eventDistanceToNextLocation: u32,
-    eventNextLocation: u32,
-    eventPosition: u32,
-}
-
-impl Focus {
-    pub(crate) fn new() -> Focus {
-        Focus {
-            name: String::from("--"),
-            country:  String::from("--"),
-            team: String::from("--"),
-            teamCode: String::from("--"),
-            power: 0,
-            avgPower: 0,
-            nrmPower: 0,
-            maxPower: 0,
-            cadence: 0,
-            avgCadence: 0,
-            maxCadence: 0,
-            heartrate: 0,
-            avgHeartrate: 0,
-            maxHeartrate: 0,
-            time: 0,
-            distance: 0,
-            height: 0,
-            speed: 0,
-            tss: 0,
-            calories: 0,
-            draft: 0,
-            windSpeed: 0,
-            windAngle: 0,
-            slope: 0,
-            eventLapsTotal: 0,
-            eventLapsDone: 0,
-            eventDistanceTotal: 0,
-            eventDistanceDone: 0,
-            eventDistanceToNextLocation: 0,
-            eventNextLocation: 0,
-            eventPosition: 0,
+mod export;
+mod influx;
+mod info;
+mod input;
+mod keyboard;
+mod output;
+mod powercurve;
+mod progress;
+mod server;
+mod stats;
+mod validate;
+mod websocket;
+
+use output::Sink;
+use tpvfitplay::{
+    frames, laps, mapping, recorded_ftp, resample_step, start_offset, value_f64, value_timestamp, warmup,
+    wind, Clock, ConvertOptions, Fields, FitDataMap, Focus, Rider, Schema, Warmup,
+};
+
+/// Print status information, onto stderr while an export is written to stdout
+macro_rules! status {
+    ($opt:expr, $($arg:tt)*) => {
+        if $opt.quiet {
+        } else if export::is_stdout(&$opt.output) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
         }
-    }
+    };
 }
 
+/// Content of the '--rider' JSON file, either one rider for all files or a roster of riders by
+/// FIT file name without extension, e.g. '{"ride_1": {"name": "Stefan"}}'
 #[derive(Clone, Debug, Deserialize)]
-#[allow(dead_code)]
-struct ValueU32 {
-    value: u32,
-    units: String,
+#[serde(untagged)]
+enum Roster {
+    Single(Rider),
+    Many(BTreeMap<String, Rider>),
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[allow(dead_code)]
-struct ValueF32 {
-    value: f32,
-    units: String,
+impl Default for Roster {
+    fn default() -> Roster {
+        Roster::Single(Rider::default())
+    }
+}
+
+impl Roster {
+    fn load(filename: &Path) -> Result<Roster, Box<dyn Error>> {
+        let fp = File::open(filename)?;
+        serde_json::from_reader(io::BufReader::new(fp))
+            .map_err(|err| format!("invalid rider file {:?}: {}", filename, err).into())
+    }
+
+    /// Rider for the FIT file with the given stem
+    fn rider(&self, stem: &str) -> Rider {
+        match self {
+            Roster::Single(rider) => rider.clone(),
+            Roster::Many(riders) => riders.get(stem).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+/// Output formats, 'focus' plays back in real time, the others export the whole activity at once
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Focus,
+    Csv,
+    Ndjson,
+    Array,
+    Gpx,
+    Tcx,
+    Influx,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "focus" => Ok(Format::Focus),
+            "csv" => Ok(Format::Csv),
+            "ndjson" => Ok(Format::Ndjson),
+            "array" => Ok(Format::Array),
+            "gpx" => Ok(Format::Gpx),
+            "tcx" => Ok(Format::Tcx),
+            "influx" => Ok(Format::Influx),
+            _ => Err(format!("unknown format '{}'", s)),
+        }
+    }
+}
+
+/// Units of the human readable outputs, the TPV 'focus.json' keeps its own scaling
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Units {
+    Metric,
+    Imperial,
+}
+
+impl FromStr for Units {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Units, String> {
+        match s {
+            "metric" => Ok(Units::Metric),
+            "imperial" => Ok(Units::Imperial),
+            _ => Err(format!("unknown units '{}'", s)),
+        }
+    }
+}
+
+impl Units {
+    /// Speed from m/s in km/h or mph
+    fn speed(self, speed: f64) -> f64 {
+        match self {
+            Units::Metric => speed * 3.6,
+            Units::Imperial => speed * 3.6 / 1.609344,
+        }
+    }
+
+    /// Distance from meters in km or miles
+    fn distance(self, distance: f64) -> f64 {
+        match self {
+            Units::Metric => distance / 1000.0,
+            Units::Imperial => distance / 1609.344,
+        }
+    }
+
+    /// Height from meters in meters or feet
+    fn height(self, height: f64) -> f64 {
+        match self {
+            Units::Metric => height,
+            Units::Imperial => height / 0.3048,
+        }
+    }
+
+    fn speed_unit(self) -> &'static str {
+        match self {
+            Units::Metric => "kmh",
+            Units::Imperial => "mph",
+        }
+    }
+
+    /// Speed unit for text, the plain unit names the CSV columns
+    fn speed_label(self) -> &'static str {
+        match self {
+            Units::Metric => "km/h",
+            Units::Imperial => "mph",
+        }
+    }
+
+    fn distance_unit(self) -> &'static str {
+        match self {
+            Units::Metric => "km",
+            Units::Imperial => "mi",
+        }
+    }
+
+    fn height_unit(self) -> &'static str {
+        match self {
+            Units::Metric => "m",
+            Units::Imperial => "ft",
+        }
+    }
+}
+
+// tools besides playback, the FIT files are played back if none is given
+#[derive(Debug, StructOpt)]
+#[allow(clippy::large_enum_variant)]
+enum Command {
+    /// Check that a FIT file decodes and count its messages, without playing it back
+    Validate {
+        /// FIT file to check, may be gzip compressed or a zip archive
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Print start time, duration, distance, sport, device and laps of each session
+    Info {
+        /// FIT file to summarize, may be gzip compressed or a zip archive
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+
+        /// Show distances in 'metric' (km) or 'imperial' (miles) units
+        #[structopt(long, default_value = "metric", possible_values = &["metric", "imperial"])]
+        units: Units,
+    },
+    /// Print the totals, averages and maximums of each activity, without playing it back
+    Stats {
+        /// FIT file to evaluate, may be gzip compressed or a zip archive
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+
+        /// Show distances and heights in 'metric' (km, m) or 'imperial' (miles, feet) units
+        #[structopt(long, default_value = "metric", possible_values = &["metric", "imperial"])]
+        units: Units,
+
+        #[structopt(flatten)]
+        convert: ConvertOptions,
+    },
+    /// Print the best average power over a set of durations, the power curve of each activity
+    Powercurve {
+        /// FIT file to evaluate, may be gzip compressed or a zip archive
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+
+        /// Durations of the best efforts, as seconds or like '30s', '5m' or '1h30m'
+        #[structopt(long, default_value = "5s,30s,1m,5m,20m,60m")]
+        durations: powercurve::Durations,
+
+        #[structopt(flatten)]
+        convert: ConvertOptions,
+    },
 }
 
 /// Read FIT formatted files and output each waypoint as TPV 'focus.json' file
 #[derive(Debug, StructOpt)]
-#[structopt(name = "tpvfitplay")]
+#[structopt(name = "tpvfitplay", setting = structopt::clap::AppSettings::ArgsNegateSubcommands)]
 struct Cli {
-    /// FIT files to read and play back as TPV 'focus.json'
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    /// FIT files to read and play back as TPV 'focus.json', may be gzip compressed, zip archives
+    /// or directories searched for FIT files
     #[structopt(name = "FILE", parse(from_os_str))]
     files: Vec<PathBuf>,
 
-    /// Output file location, if not provided the JSON file will be named 'focus.json'
+    /// Output file location, if not provided the JSON file will be named 'focus.json', '-'
+    /// plays the frames back to stdout, one per line
     #[structopt(short, long, parse(from_os_str), default_value = "focus.json")]
     output: PathBuf,
 
-    /// Delay between updates of 'focus.json' in msec.
+    /// Output format: 'focus' plays back the TPV 'focus.json', 'csv', 'ndjson' (one focus
+    /// per line), 'array' (all focus in one JSON array), 'gpx' (GPS track), 'tcx' (Training
+    /// Center XML) and 'influx' (InfluxDB line protocol) write all records at once, use '-o -'
+    /// to write those to stdout
+    #[structopt(
+        long,
+        default_value = "focus",
+        possible_values = &["focus", "csv", "ndjson", "array", "gpx", "tcx", "influx"]
+    )]
+    format: Format,
+
+    /// Units of the CSV export: 'metric' (km, km/h, m) or 'imperial' (miles, mph, feet)
+    #[structopt(long, default_value = "metric", possible_values = &["metric", "imperial"])]
+    units: Units,
+
+    /// Play back the focus frames of an 'array' or 'ndjson' export instead of FIT files
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["FILE", "merge", "simultaneous"])]
+    from_json: Option<PathBuf>,
+
+    /// JSON file with the rider's name, country, team and teamCode, or a roster of those keyed
+    /// by FIT file name without extension
+    #[structopt(long, parse(from_os_str))]
+    rider: Option<PathBuf>,
+
+    /// JSON file with the simulated wind (km/h and degrees) by elapsed seconds, replaces the
+    /// 'wind_speed' and 'wind_heading' the records may carry
+    #[structopt(long, parse(from_os_str))]
+    wind_from: Option<PathBuf>,
+
+    /// TOML file mapping FIT fields (including developer fields) to focus fields, each with an
+    /// optional scale and offset, e.g. '[power]' with 'field = "Trainer Power"'
+    #[structopt(long, parse(from_os_str))]
+    map: Option<PathBuf>,
+
+    /// Serve the frames on 'GET /focus.json' and as events on 'GET /events' at this address (e.g. 127.0.0.1:8080)
+    /// instead of writing the output file,
+    /// 'POST /pause', '/resume' and '/seek?t=<seconds>' control the playback, 'GET /state' tells where it is
+    #[structopt(long)]
+    serve: Option<String>,
+
+    /// Push the frames to WebSocket clients connecting to this address (e.g. 127.0.0.1:8081)
+    #[structopt(long)]
+    ws: Option<String>,
+
+    /// Write the frames to this InfluxDB endpoint (e.g. 'http://localhost:8086/write?db=rides'), at the
+    /// wall clock time while playing back, '--format influx' posts them at the recorded time instead of
+    /// writing the output file
+    #[structopt(long)]
+    influx_url: Option<String>,
+
+    /// API token for '--influx-url'
+    #[structopt(long, requires = "influx-url")]
+    influx_token: Option<String>,
+
+    /// Rewrite the output file in place instead of replacing it by a temporary file, for
+    /// readers which keep the file open, a reader may see a partially written frame
+    #[structopt(long)]
+    in_place: bool,
+
+    /// Write the environment (slope, windSpeed, windAngle, temperature and height) of each frame to this JSON
+    /// file, the frames keep the rider metrics only
+    #[structopt(long, parse(from_os_str))]
+    env_output: Option<PathBuf>,
+
+    /// Pretty-print the focus frames for reading them while debugging, compact by default
+    #[structopt(long)]
+    pretty: bool,
+
+    /// Only write these comma separated focus fields to the frames and the CSV columns, e.g.
+    /// 'power,heartrate,speed' for an overlay which shows a few
+    #[structopt(long)]
+    fields: Option<Fields>,
+
+    /// Field set of the frames for the overlay's TPV release: 'v1' has the fields of the first releases, the
+    /// rider, power, cadence, heart rate, time, distance, height, speed, TSS, calories, draft, wind, slope and
+    /// event fields; 'v2' adds powerBalance, the zones, wkg, timeMs, movingTime, ascent and descent, vam, the
+    /// average and max speed, IF, VI, EF, workKj, wBal, temperature, the position and finished
+    #[structopt(long, default_value = "v2", possible_values = &["v1", "v2"])]
+    schema: Schema,
+
+    /// Blank the rider name, country and team and drop all GPS positions from the exports,
+    /// for sharing a ride, the 'focus' playback keeps them. Developer fields only reach the
+    /// exports as the metrics '--map' and '--draft-field' route them to, which are kept
+    #[structopt(long)]
+    anonymize: bool,
+
+    /// Send each frame as UDP datagram to this address (e.g. 192.168.1.10:9000) instead of writing the output file
+    #[structopt(long)]
+    udp: Option<String>,
+
+    /// Delay between updates of 'focus.json' in msec per recorded second.
+    ///
+    /// The delay paces playback by the recorded time, independent of how many frames
+    /// '--rate' emits per second: with '--rate 4' four frames share one delay period.
     #[structopt(short, long, default_value = "250")]
     delay: u64,
 
+    /// Never sleep between the frames, same as '--delay 0', for tests comparing the output
+    #[structopt(long, hidden = true)]
+    no_delay: bool,
+
+    /// Wait at most this many seconds on recording gaps (e.g. auto-pause), the reported time keeps the real gap
+    #[structopt(long)]
+    compress_gaps: Option<u32>,
+
+    /// Send one frame per key press instead of pacing them: Enter or the right arrow sends the next frame, the
+    /// left arrow steps back one frame
+    #[structopt(long, conflicts_with = "simultaneous")]
+    step: bool,
+
+    /// Playback speed multiplier, e.g. 4.0 plays four times faster, 0 plays as fast as possible
+    #[structopt(long, default_value = "1.0")]
+    speed: f64,
+
+    #[structopt(flatten)]
+    convert: ConvertOptions,
+
+    /// Only play the records of this session of a multi-session file, e.g. the bike leg of a
+    /// triathlon, 0 (the first) if not given, with '--sport' all sessions of the sport
+    #[structopt(long)]
+    session: Option<usize>,
+
+    /// Only play the sessions of this sport, e.g. 'cycling' or 'running' for the bike or run
+    /// legs of a multisport file
+    #[structopt(long)]
+    sport: Option<String>,
+
+    /// Only play the records of this lap (1 for the first), e.g. to replay one interval, with
+    /// '--loop' over and over
+    #[structopt(long)]
+    lap: Option<usize>,
+
+    /// Merge all files into one continuous activity, ordered by their start time
+    #[structopt(long)]
+    merge: bool,
+
+    /// Play all files at the same time, one rider per file
+    #[structopt(long, conflicts_with = "merge")]
+    simultaneous: bool,
+
+    /// Write the frames of each rider to 'focus_<rider>.json' in this directory instead of all riders to one
+    /// output file, named after the '--rider' name or the file stem
+    #[structopt(long, parse(from_os_str), requires = "simultaneous")]
+    output_dir: Option<PathBuf>,
+
+    /// Restart playback from the first record after reaching the end
+    #[structopt(long = "loop")]
+    loop_playback: bool,
+
+    /// Wait with the first frame until this local time (HH:MM or HH:MM:SS), e.g. to start the riders
+    /// replayed on several machines together
+    #[structopt(long, parse(try_from_str = parse_clock_time))]
+    start_at: Option<NaiveTime>,
+
+    /// Start at '--start-at' tomorrow if the time already passed today, instead of failing
+    #[structopt(long, requires = "start-at")]
+    start_at_tomorrow: bool,
+
     /// Drop fields and messages that aren't defined in the profile
     #[structopt(long)]
     drop_unknown: bool,
@@ -133,87 +422,776 @@ struct Cli {
     /// Skip checking the header and data section CRC values
     #[structopt(long)]
     no_crc_check: bool,
+
+    /// Play back the records up to a decode error of a corrupt or truncated file instead of
+    /// failing, implies '--no-crc-check'
+    #[structopt(long)]
+    recover: bool,
+
+    /// Log which focus fields each record sets, twice (-vv) to also log every FIT field
+    /// name and value, RUST_LOG filters further
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Only print errors, no status or progress
+    #[structopt(short, long, conflicts_with = "verbose")]
+    quiet: bool,
 }
 
-/// Alternate serialization format
-#[derive(Clone, Debug, Serialize)]
-struct FitDataMap {
-    kind: fitparser::profile::MesgNum,
-    fields: BTreeMap<String, fitparser::ValueWithUnits>,
-}
-
-impl FitDataMap {
-    fn new(record: fitparser::FitDataRecord) -> Self {
-        FitDataMap {
-            kind: record.kind(),
-            fields: record
-                .into_vec()
-                .into_iter()
-                .map(|f| (f.name().to_owned(), fitparser::ValueWithUnits::from(f)))
-                .collect(),
-        }
-    }
-}
-
-fn write_json_file_focus(
-    filename: &Path,
-    data: Vec<fitparser::FitDataRecord>, delay: u64) -> Result<(), Box<dyn Error>> {
-    let data: Vec<FitDataMap> = data.into_iter().map(FitDataMap::new).collect();
-
-    let mut ts: u32 = 0;
-
-    for fdm in data {
-        if fdm.kind == MesgNum::Record {
-            let mut focus = Focus::new();
-
-            focus.time = ts;
-            ts += 1;
-
-            for field in fdm.fields {
-                // println!("{} = {}", field.0, field.1);
-                let tmp = serde_json::to_string(&field.1)?;
-                if field.0 == "power" {
-                    let value_u32: ValueU32 = serde_json::from_str(&tmp)?;
-                    focus.power = value_u32.value;
-                } else if field.0 == "heart_rate" {
-                    let value_u32: ValueU32 = serde_json::from_str(&tmp)?;
-                    focus.heartrate = value_u32.value;
-                } else if field.0 == "cadence" {
-                    let value_u32: ValueU32 = serde_json::from_str(&tmp)?;
-                    focus.cadence = value_u32.value;
-                } else if field.0 == "distance" {
-                    let value_f32: ValueF32 = serde_json::from_str(&tmp)?;
-                    focus.distance = value_f32.value as u32;
-                } else if field.0 == "enhanced_speed" {
-                    let value_f32: ValueF32 = serde_json::from_str(&tmp)?;
-                    focus.speed = (value_f32.value * 3.6 * 275.0) as u32;
-                } else if field.0 == "grade" {
-                    let value_f32: ValueF32 = serde_json::from_str(&tmp)?;
-                    focus.slope = value_f32.value as i32;
-                } else if field.0 == "enhanced_altitude" {
-                    let value_f32: ValueF32 = serde_json::from_str(&tmp)?;
-                    focus.height = 450 + value_f32.value as u32;
+/// Join activities into one continuous timeline ordered by their first record timestamp.
+///
+/// Records overlapping the end of the previous activity are dropped and the distance of the
+/// following activities continues from where the previous one stopped.
+fn merge(mut activities: Vec<Vec<FitDataMap>>) -> Vec<FitDataMap> {
+    let first_record = |data: &[FitDataMap]| {
+        data.iter()
+            .filter(|fdm| fdm.kind == MesgNum::Record)
+            .find_map(|fdm| fdm.timestamp)
+    };
+    activities.sort_by_key(|data| first_record(data).unwrap_or(i64::MAX));
+
+    let mut merged: Vec<FitDataMap> = Vec::new();
+    let mut last_timestamp: Option<i64> = None;
+    let mut last_distance = 0.0;
+
+    for data in activities {
+        let offset = last_distance;
+        let seam = last_timestamp;
+        for mut fdm in data {
+            if fdm.kind == MesgNum::Record {
+                if let (Some(t), Some(seam)) = (fdm.timestamp, seam) {
+                    if t <= seam {
+                        continue;
+                    }
                 }
+                if let Some(distance) = fdm.fields.get("distance").and_then(value_f64) {
+                    last_distance = distance + offset;
+                    fdm.fields
+                        .insert(String::from("distance"), Value::Float64(last_distance));
+                }
+                last_timestamp = fdm.timestamp.or(last_timestamp);
+            }
+            merged.push(fdm);
+        }
+    }
+    merged
+}
+
+/// Join the activities for '--merge', the sessions are selected in each of them as every file
+/// brings its own
+fn merge_activities(read: Vec<input::Activity>, opt: &Cli) -> Result<Vec<FitDataMap>, Box<dyn Error>> {
+    let mut activities = Vec::new();
+    for activity in read {
+        status!(opt, "Reading from: {:?}", activity.name);
+        activities.push(select_sessions(FitDataMap::from_records(activity.records), opt)?);
+    }
+    Ok(merge(activities))
+}
+
+/// Records, laps and session messages of the sessions selected by '--session' and '--sport',
+/// listing the recorded sessions or sports if none matches
+fn select_sessions(data: Vec<FitDataMap>, opt: &Cli) -> Result<Vec<FitDataMap>, Box<dyn Error>> {
+    let sessions: Vec<&FitDataMap> = data.iter().filter(|fdm| fdm.kind == MesgNum::Session).collect();
+    // the first session is the default, a file of a single session is played as a whole and
+    // '--sport' on its own plays all sessions of the sport
+    if opt.session.is_none() && opt.sport.is_none() && sessions.len() <= 1 {
+        return Ok(data);
+    }
+    let selected = opt.session.or(opt.sport.is_none().then_some(0));
+    // the 'Sport' message names the sport of files which don't record it per session
+    let file_sport = data
+        .iter()
+        .filter(|fdm| fdm.kind == MesgNum::Sport)
+        .find_map(|fdm| fdm.fields.get("sport"))
+        .map(|v| v.to_string());
+    let sport_of = |session: &FitDataMap| {
+        session.fields.get("sport").map(|v| v.to_string()).or_else(|| file_sport.clone())
+    };
+
+    if let Some(index) = selected {
+        if index >= sessions.len() {
+            let mut msg = format!("--session {} out of range, {} sessions recorded", index, sessions.len());
+            for (index, session) in sessions.iter().enumerate() {
+                msg += &format!(
+                    "\n  {}: {} {}, {}",
+                    index,
+                    info::time(session, "start_time"),
+                    info::duration(session.fields.get("total_elapsed_time").and_then(value_f64)),
+                    sport_of(session).unwrap_or_else(|| String::from("--")),
+                );
+            }
+            return Err(msg.into());
+        }
+    }
+    if let Some(sport) = &opt.sport {
+        if !sessions.iter().any(|session| sport_of(session).as_ref() == Some(sport)) {
+            let mut recorded: Vec<String> = sessions.iter().filter_map(|session| sport_of(session)).collect();
+            recorded.sort();
+            recorded.dedup();
+            let recorded = if recorded.is_empty() { String::from("none") } else { recorded.join(", ") };
+            return Err(format!("no session of sport '{}', sports recorded: {}", sport, recorded).into());
+        }
+    }
+
+    let mut ranges: Vec<(i64, i64)> = Vec::new();
+    for (index, session) in sessions.iter().enumerate() {
+        if selected.is_some_and(|selected| selected != index)
+            || opt.sport.as_ref().is_some_and(|sport| sport_of(session).as_ref() != Some(sport))
+        {
+            continue;
+        }
+        let start = session.fields.get("start_time").and_then(value_timestamp);
+        let elapsed = session.fields.get("total_elapsed_time").and_then(value_f64);
+        match (start, session.timestamp.or_else(|| Some(start? + elapsed?.ceil() as i64))) {
+            (Some(start), Some(end)) => ranges.push((start, end)),
+            _ => return Err(format!("session {} has no start and end time", index).into()),
+        }
+    }
+
+    let within = |t: Option<i64>| t.is_some_and(|t| ranges.iter().any(|(start, end)| t >= *start && t <= *end));
+    Ok(data
+        .into_iter()
+        .filter(|fdm| match fdm.kind {
+            MesgNum::Record | MesgNum::Lap => within(fdm.timestamp),
+            MesgNum::Session => {
+                let start = fdm.fields.get("start_time").and_then(value_timestamp);
+                ranges.iter().any(|range| Some(range.0) == start)
+            }
+            _ => true,
+        })
+        .collect())
+}
+
+/// Records of the lap selected by '--lap' with its lap message, the other messages are kept
+fn select_lap(data: Vec<FitDataMap>, opt: &Cli) -> Result<Vec<FitDataMap>, Box<dyn Error>> {
+    let number = match opt.lap {
+        Some(number) => number,
+        None => return Ok(data),
+    };
+    let laps = laps(&data);
+    if number == 0 || number > laps.len() {
+        let mut msg = format!("--lap {} out of range, {} laps recorded (1 is the first)", number, laps.len());
+        for (index, lap) in laps.iter().enumerate() {
+            msg += &format!("\n  {}: {}", index + 1, info::duration(lap.elapsed));
+        }
+        return Err(msg.into());
+    }
+    let lap = &laps[number - 1];
+
+    let within = |t: Option<i64>| t.is_some_and(|t| t >= lap.start && t <= lap.end);
+    Ok(data
+        .into_iter()
+        .filter(|fdm| match fdm.kind {
+            MesgNum::Record => within(fdm.timestamp),
+            MesgNum::Lap => fdm.timestamp == Some(lap.end),
+            _ => true,
+        })
+        .collect())
+}
+
+fn influx_endpoint(url: &str, opt: &Cli) -> influx::Endpoint {
+    influx::Endpoint::new(url, opt.influx_token.as_deref())
+}
+
+/// Play back the frames, once or repeatedly with '--loop', `total` is the elapsed time of the
+/// last frame
+fn write_json_file_focus<I: Iterator<Item = Focus>>(
+    outputs: &mut [Box<dyn Sink>],
+    frames: impl Fn() -> I, total: Option<f64>, opt: &Cli,
+    pause: &keyboard::Pause) -> Result<(), Box<dyn Error>> {
+    let mut pass = 1;
+    let mut seek = None;
+    pause.set_seekable(true);
+    loop {
+        // a seek plays the frames again from the start, the metrics before the target are
+        // calculated but not sent
+        let from = seek.take().unwrap_or(0);
+        let frames = frames().skip_while(|focus| focus.time < from);
+        if let Played::Seek(time) = play_frames(outputs, frames, total, opt, pause)? {
+            seek = Some(time);
+            continue;
+        }
+        if !opt.loop_playback || pause.is_stopped() {
+            break;
+        }
+        pass += 1;
+        status!(opt, "- restarting playback (pass {})", pass);
+    }
+    pause.set_seekable(false);
+    Ok(())
+}
+
+/// Focus frame as sent to the sinks
+fn focus_json<T: Borrow<Focus>>(focus_list: &[T], opt: &Cli) -> serde_json::Result<String> {
+    let frames: Vec<_> = focus_list.iter().map(|f| opt.schema.frame(f.borrow())).collect();
+    match &opt.fields {
+        Some(fields) => {
+            let selected: Vec<_> = frames.iter().map(|frame| fields.select(frame)).collect();
+            to_json(&selected, opt)
+        }
+        None => to_json(&frames, opt),
+    }
+}
+
+/// Send the frame of the focus list to all outputs, the sinks tagging by rider get the names
+/// of the focus
+fn send_frame<T: Borrow<Focus>>(
+    outputs: &mut [Box<dyn Sink>],
+    focus_list: &[T],
+    opt: &Cli,
+) -> Result<(), Box<dyn Error>> {
+    let names: Vec<&str> = focus_list.iter().map(|focus| focus.borrow().name.as_str()).collect();
+    send_named_frame(outputs, focus_list, &names, opt)
+}
+
+/// Send the frame of the focus list to all outputs with the names of its riders
+fn send_named_frame<T: Borrow<Focus>>(
+    outputs: &mut [Box<dyn Sink>],
+    focus_list: &[T],
+    names: &[&str],
+    opt: &Cli,
+) -> Result<(), Box<dyn Error>> {
+    let json = focus_json(focus_list, opt)?;
+    for output in outputs.iter_mut() {
+        output.send_named(&json, names)?;
+    }
+    Ok(())
+}
+
+fn to_json<T: Serialize>(value: &T, opt: &Cli) -> serde_json::Result<String> {
+    if opt.pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    }
+}
+
+/// Time to wait per recorded second, None if playback should not wait at all
+fn frame_delay(opt: &Cli) -> Option<time::Duration> {
+    checked_delay(opt).ok().flatten()
+}
+
+/// Time to wait per recorded second, fails if '--speed' is too small for a wait to fit a
+/// Duration, checked once before playback
+fn checked_delay(opt: &Cli) -> Result<Option<time::Duration>, String> {
+    if opt.no_delay || opt.delay == 0 || opt.speed <= 0.0 {
+        return Ok(None);
+    }
+    time::Duration::try_from_secs_f64(opt.delay as f64 / 1000.0 / opt.speed)
+        .map(Some)
+        .map_err(|_| format!("--speed is too small for --delay {}", opt.delay))
+}
+
+/// Paces the frames by the wall clock, each frame is due at a fixed time from the start, so
+/// the time spent serializing and writing the frames doesn't add up over a long playback
+struct Pacer {
+    start: time::Instant,
+    due: time::Duration,
+}
+
+impl Pacer {
+    fn new() -> Pacer {
+        Pacer {
+            start: time::Instant::now(),
+            due: time::Duration::ZERO,
+        }
+    }
+
+    /// Sleep until the frame `wait` after the previous one is due, returns at once if
+    /// playback fell behind
+    fn wait(&mut self, wait: time::Duration, pause: &keyboard::Pause) {
+        self.due += wait;
+        // parked instead of sleeping, so stopping doesn't wait for a long recording gap
+        while !pause.is_stopped() && !pause.is_seeking() {
+            let elapsed = self.start.elapsed();
+            if self.due <= elapsed {
+                break;
             }
-            
-            let focus_list = vec![focus];
-            let json = serde_json::to_string(&focus_list)?;
-            // print!("{focus_list:#?}");
-            print!("- processing time-stamp: {:5}", ts);
+            thread::park_timeout(self.due - elapsed);
+        }
+    }
 
-            // let mut fp = File::create("/home/stefan/devel/tpvbc2http/http/testing/focus.json")?;
-            let mut fp = File::create(filename)?;
-            fp.write_all(json.as_bytes())?;
+    /// Count the next frames from now, e.g. after playback was paused
+    fn restart(&mut self) {
+        *self = Pacer::new();
+    }
+}
 
-            thread::sleep(time::Duration::from_millis(delay));
-            println!("\x1b[5D\x1b[1A");
+/// Tell where the FTP for the TSS comes from
+fn report_ftp(data: &[FitDataMap], opt: &Cli) {
+    match (opt.convert.ftp, recorded_ftp(data)) {
+        (Some(ftp), _) => status!(opt, "FTP         : {} W (--ftp)", ftp),
+        (None, Some(ftp)) => status!(opt, "FTP         : {} W (recorded in the file)", ftp),
+        (None, None) => {
+            status!(opt, "No FTP given (--ftp) or recorded in the file, skipping TSS calculation");
+            if opt.convert.coggan_zones {
+                status!(opt, "No FTP to derive the Coggan zones from, leaving powerZone at 0");
+            }
         }
     }
+}
+
+/// Tell where '--skip-warmup' starts the playback
+fn report_warmup(data: &[FitDataMap], opt: &Cli) {
+    if !opt.convert.skip_warmup {
+        return;
+    }
+    let start = |end: u32| info::duration(Some(end.max(opt.convert.start) as f64));
+    match warmup(data, &opt.convert) {
+        Some(Warmup::Power(end)) => status!(
+            opt,
+            "Warmup      : skipped, starting at {} (30 s power reached {}% of the FTP)",
+            start(end),
+            opt.convert.warmup_threshold
+        ),
+        Some(Warmup::Lap(end)) => {
+            status!(opt, "Warmup      : skipped, starting at {} (end of the first lap)", start(end))
+        }
+        None => status!(opt, "Warmup      : not detected by power or laps, starting at {}", start(0)),
+    }
+}
+
+/// Play back all activities side by side, every frame holds one focus per rider at the same
+/// elapsed time, riders which already finished keep their last focus. The `rider_outputs` get
+/// the focus of their rider only. The rider `names` tag the frames, unnamed riders by their
+/// file, as the focus of all of them is named '--'.
+fn play_simultaneous(
+    outputs: &mut [Box<dyn Sink>], rider_outputs: &mut [Box<dyn Sink>],
+    riders: &[(Vec<FitDataMap>, Rider)], names: &[String], opt: &Cli,
+    pause: &keyboard::Pause) -> Result<(), Box<dyn Error>> {
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    let mut players: Vec<_> = riders
+        .iter()
+        .map(|(data, rider)| {
+            let mut current = Focus::new();
+            rider.apply(&mut current);
+            (frames(data, &opt.convert, rider).peekable(), current)
+        })
+        .collect();
+
+    let step = resample_step(&opt.convert).unwrap_or(1.0);
+    let mut pacer = Pacer::new();
+    // the riders start together once the first one finished the warmup
+    let mut time = riders
+        .iter()
+        .map(|(data, _)| start_offset(data, &opt.convert))
+        .min()
+        .unwrap_or(opt.convert.start) as f64;
+    let total = riders
+        .iter()
+        .filter_map(|(data, _)| playback_end(data, opt))
+        .reduce(f64::max);
+    let mut progress = progress::Progress::new(time, total, opt);
+    loop {
+        let mut running = false;
+        for (frames, current) in players.iter_mut() {
+            while let Some(focus) = frames.next_if(|f| f.elapsed <= time) {
+                *current = focus;
+            }
+            running |= frames.peek().is_some();
+        }
+
+        let focus_list: Vec<&Focus> = players.iter().map(|(_, focus)| focus).collect();
+        send_named_frame(outputs, &focus_list, &names, opt)?;
+        for ((output, focus), name) in rider_outputs.iter_mut().zip(&focus_list).zip(&names) {
+            send_named_frame(std::slice::from_mut(output), std::slice::from_ref(focus), &[name], opt)?;
+        }
+        pause.set_position(time as u32);
+        progress.update(time);
+
+        if !running {
+            break;
+        }
+        if let Some(delay) = frame_delay(opt) {
+            pacer.wait(delay.mul_f64(step), pause);
+        }
+        if pause.wait() {
+            pacer.restart();
+        }
+        if pause.is_stopped() {
+            let mut last: Vec<Focus> = players.iter().map(|(_, focus)| focus.clone()).collect();
+            last.iter_mut().for_each(|focus| focus.finished = true);
+            send_named_frame(outputs, &last, &names, opt)?;
+            for ((output, focus), name) in rider_outputs.iter_mut().zip(&last).zip(&names) {
+                send_named_frame(std::slice::from_mut(output), std::slice::from_ref(focus), &[name], opt)?;
+            }
+            break;
+        }
+        time += step;
+    }
+    progress.finish();
     Ok(())
 }
 
+/// 'focus_<name>.json' in `dir`, characters unsafe in file names replaced by '_' and a
+/// number appended to a name `taken` by another rider already
+fn rider_filename(dir: &Path, name: &str, taken: &mut HashSet<String>) -> PathBuf {
+    let safe: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let safe = if safe.is_empty() { String::from("rider") } else { safe };
+    let mut unique = safe.clone();
+    let mut count = 1;
+    while !taken.insert(unique.to_lowercase()) {
+        count += 1;
+        unique = format!("{}_{}", safe, count);
+    }
+    dir.join(format!("focus_{}.json", unique))
+}
+
+/// Elapsed seconds of the last frame played back, capped by '--end', None if the records
+/// carry no timestamps
+fn playback_end(data: &[FitDataMap], opt: &Cli) -> Option<f64> {
+    let mut timestamps = data
+        .iter()
+        .filter(|fdm| fdm.kind == MesgNum::Record)
+        .filter_map(|fdm| fdm.timestamp);
+    let first = timestamps.next()?;
+    let last = timestamps.next_back().unwrap_or(first);
+    let mut clock = Clock::new(data, &opt.convert);
+    clock.tick(Some(first as f64));
+    let (duration, _) = clock.tick(Some(last as f64));
+    Some(opt.convert.end.map_or(duration, |end| duration.min(end as f64)))
+}
+
+/// How a single pass over the frames ended
+enum Played {
+    /// all frames were sent or playback was stopped
+    Done,
+    /// playback is asked to continue at this recorded time
+    Seek(u32),
+}
+
+/// Play back the frames once, paced by their elapsed time
+fn play_frames(
+    outputs: &mut [Box<dyn Sink>],
+    frames: impl Iterator<Item = Focus>, total: Option<f64>, opt: &Cli,
+    pause: &keyboard::Pause) -> Result<Played, Box<dyn Error>> {
+    if opt.step {
+        return step_frames(outputs, frames, total, opt, pause);
+    }
+    let mut prev_time: Option<f64> = None;
+    let mut pacer = Pacer::new();
+    // '--skip-warmup' may start later than '--start'
+    let mut frames = frames.peekable();
+    let start = frames.peek().map_or(opt.convert.start as f64, |focus| focus.elapsed);
+    let mut progress = progress::Progress::new(start, total, opt);
+    let mut last: Vec<Focus> = Vec::new();
+
+    for focus in frames {
+        // pace by the recorded time between the frames, long gaps optionally capped
+        if let (Some(prev), Some(delay)) = (prev_time, frame_delay(opt)) {
+            let gap = (focus.elapsed - prev).max(0.0);
+            let gap = opt.compress_gaps.map_or(gap, |max| gap.min(max as f64));
+            pacer.wait(delay.mul_f64(gap), pause);
+        }
+        if pause.wait() {
+            pacer.restart();
+        }
+        if pause.is_stopped() {
+            send_finished(outputs, &mut last, opt)?;
+            break;
+        }
+        if let Some(time) = pause.take_seek() {
+            progress.finish();
+            return Ok(Played::Seek(time));
+        }
+        prev_time = Some(focus.elapsed);
+
+        let elapsed = focus.elapsed;
+        let time = focus.time;
+        let focus_list = vec![focus];
+        // print!("{focus_list:#?}");
+
+        send_frame(outputs, &focus_list, opt)?;
+        pause.set_position(time);
+        progress.update(elapsed);
+        last = focus_list;
+    }
+    progress.finish();
+    Ok(Played::Done)
+}
+
+/// Frames '--step' can step back
+const STEP_HISTORY: usize = 1000;
+
+/// Send one frame per key press for '--step', the frames sent last are kept to step back
+fn step_frames(
+    outputs: &mut [Box<dyn Sink>],
+    mut frames: impl Iterator<Item = Focus>, total: Option<f64>, opt: &Cli,
+    pause: &keyboard::Pause) -> Result<Played, Box<dyn Error>> {
+    let mut history: VecDeque<Focus> = VecDeque::new();
+    // frames stepped back from the latest one
+    let mut back = 0;
+    let mut progress: Option<progress::Progress> = None;
+
+    loop {
+        let step = pause.wait_step();
+        if pause.is_stopped() {
+            let shown = history.len().checked_sub(back + 1).map(|index| history[index].clone());
+            send_finished(outputs, &mut Vec::from_iter(shown), opt)?;
+            break;
+        }
+        if let Some(time) = pause.take_seek() {
+            if let Some(progress) = &progress {
+                progress.finish();
+            }
+            return Ok(Played::Seek(time));
+        }
+        match step {
+            Some(keyboard::Step::Back) if back + 1 < history.len() => back += 1,
+            Some(keyboard::Step::Forward) if back > 0 => back -= 1,
+            Some(keyboard::Step::Forward) => match frames.next() {
+                Some(focus) => {
+                    history.push_back(focus);
+                    if history.len() > STEP_HISTORY {
+                        history.pop_front();
+                    }
+                }
+                None => break,
+            },
+            _ => continue,
+        }
+
+        let focus = &history[history.len() - 1 - back];
+        send_frame(outputs, std::slice::from_ref(focus), opt)?;
+        pause.set_position(focus.time);
+        progress
+            .get_or_insert_with(|| progress::Progress::new(focus.elapsed, total, opt))
+            .update(focus.elapsed);
+    }
+    if let Some(progress) = &progress {
+        progress.finish();
+    }
+    Ok(Played::Done)
+}
+
+/// Send the frame played last again, marked as finished, after playback was stopped
+fn send_finished(outputs: &mut [Box<dyn Sink>], last: &mut [Focus], opt: &Cli) -> Result<(), Box<dyn Error>> {
+    for focus in last.iter_mut() {
+        focus.finished = true;
+    }
+    if !last.is_empty() {
+        send_frame(outputs, last, opt)?;
+    }
+    Ok(())
+}
+
+/// What to do with the records of each input
+enum Mode {
+    /// Play back the focus frames in real time to all sinks
+    Playback(Vec<Box<dyn Sink>>),
+    /// Export all records as CSV in one go
+    Csv(export::CsvExport<Box<dyn Write>>),
+    /// Export all focus frames as newline delimited JSON in one go
+    Ndjson(Box<dyn Write>),
+    /// Export all focus frames as a single JSON array
+    Array(export::ArrayExport),
+    /// Export the GPS track as GPX
+    Gpx(export::GpxExport),
+    /// Export the activity as Garmin Training Center XML
+    Tcx(export::TcxExport),
+    /// Export all focus frames as InfluxDB line protocol
+    Influx(influx::InfluxExport),
+}
+
+impl Mode {
+    fn create(opt: &Cli, pause: &keyboard::Pause) -> Result<Mode, Box<dyn Error>> {
+        let mode = match opt.format {
+            Format::Focus => {
+                let mut outputs: Vec<Box<dyn Sink>> = Vec::new();
+                if let Some(addr) = &opt.serve {
+                    outputs.push(Box::new(server::serve(addr, pause)?));
+                }
+                if let Some(addr) = &opt.udp {
+                    outputs.push(Box::new(output::UdpSink::new(addr)?));
+                }
+                if opt.serve.is_none() && opt.udp.is_none() && opt.output_dir.is_none() {
+                    if export::is_stdout(&opt.output) {
+                        outputs.push(Box::new(output::StdoutSink));
+                    } else {
+                        outputs.push(Box::new(output::FileSink::new(&opt.output, !opt.in_place)));
+                    }
+                }
+                if let Some(addr) = &opt.ws {
+                    outputs.push(Box::new(websocket::serve(addr)?));
+                }
+                if let Some(url) = &opt.influx_url {
+                    outputs.push(Box::new(influx::InfluxSink::new(influx_endpoint(url, opt))));
+                }
+                if let Some(filename) = &opt.env_output {
+                    outputs = vec![Box::new(output::EnvSplit::new(filename, outputs, opt.pretty))];
+                }
+                Mode::Playback(outputs)
+            }
+            Format::Csv => Mode::Csv(export::CsvExport::new(
+                export::create(&opt.output)?,
+                opt.units,
+                opt.fields.as_ref(),
+            )?),
+            Format::Ndjson => Mode::Ndjson(export::create(&opt.output)?),
+            Format::Array => Mode::Array(export::ArrayExport::new(export::create(&opt.output)?)?),
+            Format::Gpx => Mode::Gpx(export::GpxExport::new(export::create(&opt.output)?)?),
+            Format::Tcx => Mode::Tcx(export::TcxExport::new(export::create(&opt.output)?)?),
+            Format::Influx => match &opt.influx_url {
+                Some(url) => Mode::Influx(influx::InfluxExport::post(influx_endpoint(url, opt))),
+                None => Mode::Influx(influx::InfluxExport::new(export::create(&opt.output)?)),
+            },
+        };
+        Ok(mode)
+    }
+
+    /// Where the output goes, for the user's information
+    fn describe(&self, opt: &Cli) -> String {
+        let export = || {
+            if export::is_stdout(&opt.output) {
+                String::from("stdout")
+            } else {
+                format!("{:?}", opt.output)
+            }
+        };
+        match self {
+            Mode::Playback(outputs) => outputs
+                .iter()
+                .map(|o| o.describe())
+                .collect::<Vec<_>>()
+                .join(", "),
+            Mode::Influx(influx) => influx.url().map_or_else(export, String::from),
+            _ => export(),
+        }
+    }
+
+    fn handle(
+        &mut self,
+        data: Vec<FitDataMap>, opt: &Cli, rider: &Rider,
+        pause: &keyboard::Pause) -> Result<(), Box<dyn Error>> {
+        let mut data = select_lap(data, opt)?;
+        report_ftp(&data, opt);
+        report_warmup(&data, opt);
+        let anonymize = opt.anonymize && !matches!(self, Mode::Playback(_));
+        if anonymize {
+            export::strip_positions(&mut data);
+        }
+        let identity = move |focus| if anonymize { export::anonymize(focus) } else { focus };
+        match self {
+            // every pass starts with fresh metrics
+            Mode::Playback(outputs) => write_json_file_focus(
+                outputs,
+                || frames(&data, &opt.convert, rider),
+                playback_end(&data, opt),
+                opt,
+                pause,
+            ),
+            Mode::Csv(csv) => csv.write(&data, opt),
+            Mode::Ndjson(out) => {
+                let frames = frames(&data, &opt.convert, rider).map(identity);
+                export::write_ndjson(out, frames, opt.fields.as_ref(), opt.schema)
+            }
+            Mode::Array(array) => {
+                let frames = frames(&data, &opt.convert, rider).map(identity);
+                array.write(frames, opt.fields.as_ref(), opt.schema)
+            }
+            Mode::Gpx(gpx) => gpx.write(&data, opt),
+            Mode::Tcx(tcx) => tcx.write(&data, opt),
+            Mode::Influx(influx) => {
+                let frames = frames(&data, &opt.convert, rider).map(identity);
+                influx.write(frames, opt.fields.as_ref(), opt.schema)
+            }
+        }
+    }
+
+    /// Complete the output after the last input was handled
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Mode::Array(array) => array.finish()?,
+            Mode::Gpx(gpx) => gpx.finish()?,
+            Mode::Tcx(tcx) => tcx.finish()?,
+            Mode::Influx(influx) => influx.finish()?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Local time of '--start-at'
+fn parse_clock_time(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M"))
+        .map_err(|_| format!("invalid time '{}', expected HH:MM or HH:MM:SS", s))
+}
+
+/// When '--start-at' is next, tomorrow only with '--start-at-tomorrow'
+fn start_at(time: NaiveTime, opt: &Cli) -> Result<DateTime<Local>, Box<dyn Error>> {
+    let now = Local::now();
+    let at = |date: NaiveDate| {
+        // the earlier one if the clocks are set back at that time, an hour later if the clocks
+        // skip it
+        let local = date.and_time(time);
+        Local
+            .from_local_datetime(&local)
+            .earliest()
+            .or_else(|| Local.from_local_datetime(&(local + chrono::Duration::hours(1))).earliest())
+            .unwrap_or(now)
+    };
+    let today = at(now.date_naive());
+    if today > now {
+        return Ok(today);
+    }
+    if !opt.start_at_tomorrow {
+        return Err(format!(
+            "--start-at {} already passed today, add --start-at-tomorrow to start at that time tomorrow",
+            time.format("%H:%M:%S")
+        )
+        .into());
+    }
+    Ok(at(now.date_naive() + chrono::Duration::days(1)))
+}
+
+/// Block until `start`, Ctrl-C stops waiting
+fn wait_until(start: Option<DateTime<Local>>, opt: &Cli, pause: &keyboard::Pause) {
+    let Some(start) = start else {
+        return;
+    };
+    let wait = (start - Local::now()).to_std().unwrap_or_default();
+    status!(
+        opt,
+        "Start at    : {}, waiting {}",
+        start.format("%Y-%m-%d %H:%M:%S"),
+        info::duration(Some(wait.as_secs_f64().ceil()))
+    );
+    while !pause.is_stopped() {
+        match (start - Local::now()).to_std() {
+            Ok(left) if !left.is_zero() => thread::park_timeout(left),
+            _ => break,
+        }
+    }
+}
+
+const STEP_NO_TERMINAL: &str = "--step needs a terminal on stdin to read the keys from";
+
 fn run() -> Result<(), Box<dyn Error>> {
-    let opt = Cli::from_args();
+    let mut opt = Cli::from_args();
+
+    let level = match opt.verbose {
+        0 if opt.quiet => log::LevelFilter::Error,
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    env_logger::Builder::new().filter_level(level).parse_default_env().init();
+
+    match opt.command.take() {
+        Some(Command::Validate { file }) => return validate::run(&file),
+        Some(Command::Info { file, units }) => return info::run(&file, units),
+        Some(Command::Stats { file, units, convert }) => return stats::run(&file, units, convert),
+        Some(Command::Powercurve { file, durations, convert }) => {
+            return powercurve::run(&file, &durations, convert)
+        }
+        None => {}
+    }
+    opt.files = input::expand_globs(std::mem::take(&mut opt.files))?;
 
     // set any decode options
     let mut decode_opts = HashSet::new();
@@ -230,46 +1208,261 @@ fn run() -> Result<(), Box<dyn Error>> {
     if opt.numeric_enums {
         decode_opts.insert(DecodeOption::ReturnNumericEnumValues);
     }
-    if opt.no_crc_check {
+    if opt.no_crc_check || opt.recover {
         decode_opts.insert(DecodeOption::SkipHeaderCrcValidation);
         decode_opts.insert(DecodeOption::SkipDataCrcValidation);
     }
 
+    if let Some(end) = opt.convert.end {
+        if end < opt.convert.start {
+            return Err(format!("--end ({}) must not be before --start ({})", end, opt.convert.start).into());
+        }
+    }
+
+    if let Some(missing) = opt.fields.as_ref().and_then(|fields| fields.missing(opt.schema)) {
+        if matches!(opt.format, Format::Focus | Format::Ndjson | Format::Array | Format::Influx) {
+            return Err(format!("field '{}' is not part of the schema, use --schema v2", missing).into());
+        }
+    }
+
+    if !opt.speed.is_finite() || opt.speed < 0.0 {
+        return Err(format!("--speed ({}) must be a number of at least 0", opt.speed).into());
+    }
+    checked_delay(&opt)?;
+
+    if let Some(rate) = opt.convert.rate {
+        if rate.is_nan() || rate <= 0.0 {
+            return Err(format!("--rate ({}) must be greater than 0", rate).into());
+        }
+    }
+
+    if let Some(filename) = &opt.map {
+        opt.convert.field_map = mapping::FieldMap::load(filename)?;
+    }
+    if let Some(filename) = &opt.wind_from {
+        opt.convert.wind = wind::Wind::load(filename)?;
+    }
+
+    let roster = match &opt.rider {
+        Some(filename) => Roster::load(filename)?,
+        None => Roster::default(),
+    };
+
+    // wall clock time of the first frame
+    let start = opt.start_at.map(|time| start_at(time, &opt)).transpose()?;
+    if start.is_some() && !matches!(opt.format, Format::Focus) {
+        return Err("--start-at only works with --format focus".into());
+    }
+    if opt.from_json.is_some() && !matches!(opt.format, Format::Focus) {
+        return Err("--from-json only works with --format focus".into());
+    }
+    if opt.simultaneous && !matches!(opt.format, Format::Focus) {
+        return Err("--simultaneous only works with --format focus".into());
+    }
+
     // define parsed and serialized data output location
-    let output_loc = opt.output.as_path();
+    let pause = keyboard::Pause::new();
+    let mut mode = Mode::create(&opt, &pause)?;
+    let output_loc = mode.describe(&opt);
+
+    // Ctrl-C stops a playback with a final frame, an export is aborted
+    keyboard::handle_interrupt(&pause, matches!(mode, Mode::Playback(_)));
+
+    // play back a focus dump instead of FIT files
+    if let Some(filename) = &opt.from_json {
+        let outputs = match &mut mode {
+            Mode::Playback(outputs) => outputs,
+            _ => return Err("--from-json only works with --format focus".into()),
+        };
+        let dump = input::read_focus(filename)?;
+        status!(opt, "Reading from: {:?}", filename);
+        status!(opt, "Writing   to: {}", output_loc);
+
+        let between = || {
+            dump.iter()
+                .take_while(|focus| opt.convert.end.is_none_or(|end| focus.time <= end))
+                .filter(|focus| focus.time >= opt.convert.start)
+                .cloned()
+        };
+        let total = between().last().map(|focus| focus.elapsed);
+        if !keyboard::spawn(&pause) && opt.step {
+            return Err(STEP_NO_TERMINAL.into());
+        }
+        wait_until(start, &opt, &pause);
+        return write_json_file_focus(outputs, between, total, &opt, &pause);
+    }
 
     // read from STDIN if no files were given
     if opt.files.is_empty() {
-        println!("Reading from: stdin");
-        println!("Writing   to: {:?}", output_loc);
+        if opt.step {
+            return Err("--step reads the keys from stdin, give the FIT files as arguments".into());
+        }
+        status!(opt, "Reading from: stdin");
+        status!(opt, "Writing   to: {}", output_loc);
 
         let mut stdin = io::stdin();
-        let data = from_reader_with_options(&mut stdin, &decode_opts)?;
-        write_json_file_focus(output_loc, data, opt.delay)?;
+        let messages = Some(input::PLAYBACK_MESSAGES);
+        let data = input::read_fit(&mut stdin, "stdin", &decode_opts, opt.recover, messages)?;
+        let data = select_sessions(FitDataMap::from_records(data), &opt)?;
+        wait_until(start, &opt, &pause);
+        mode.handle(data, &opt, &roster.rider("stdin"), &pause)?;
+        return mode.finish();
+    }
+
+    // space pauses and resumes, stdin is only free for the keyboard when reading files
+    if !keyboard::spawn(&pause) && opt.step {
+        return Err(STEP_NO_TERMINAL.into());
+    }
+
+    let (read, failed) = read_all(&decode_opts, &opt);
+
+    // play all files as one activity
+    if opt.merge {
+        let data = merge_activities(read, &opt)?;
+        all_failed(failed, &opt)?;
+        status!(opt, "Writing   to: {}", output_loc);
+
+        let rider = opt.files.first().map(|f| input::stem(f)).unwrap_or_default();
+        wait_until(start, &opt, &pause);
+        mode.handle(data, &opt, &roster.rider(&rider), &pause)?;
+        return mode.finish();
+    }
+
+    // play all files at the same time as different riders
+    if opt.simultaneous {
+        let outputs = match &mut mode {
+            Mode::Playback(outputs) => outputs,
+            _ => return Err("--simultaneous only works with --format focus".into()),
+        };
+        let mut riders = Vec::new();
+        // names of the '--output-dir' files
+        let mut names = Vec::new();
+        for activity in read {
+            status!(opt, "Reading from: {:?}", activity.name);
+            let rider = roster.rider(&activity.stem);
+            names.push(rider.name.clone().unwrap_or_else(|| activity.stem.clone()));
+            let data = select_sessions(FitDataMap::from_records(activity.records), &opt)
+                .and_then(|data| select_lap(data, &opt))?;
+            report_ftp(&data, &opt);
+            report_warmup(&data, &opt);
+            riders.push((data, rider));
+        }
+        all_failed(failed, &opt)?;
+        if !outputs.is_empty() {
+            status!(opt, "Writing   to: {}", output_loc);
+        }
+
+        let mut rider_outputs: Vec<Box<dyn Sink>> = Vec::new();
+        if let Some(dir) = &opt.output_dir {
+            fs::create_dir_all(dir).map_err(|err| format!("unable to create {:?}: {}", dir, err))?;
+            let mut taken = HashSet::new();
+            for name in &names {
+                let filename = rider_filename(dir, name, &mut taken);
+                status!(opt, "Writing   to: {:?}", filename);
+                let file: Box<dyn Sink> = Box::new(output::FileSink::new(&filename, !opt.in_place));
+                // the environment of all riders goes to the '--env-output' of the shared frame
+                rider_outputs.push(match opt.env_output {
+                    Some(_) => Box::new(output::EnvSplit::strip(vec![file], opt.pretty)),
+                    None => file,
+                });
+            }
+        }
+        wait_until(start, &opt, &pause);
+
+        let mut pass = 1;
+        loop {
+            play_simultaneous(outputs, &mut rider_outputs, &riders, &names, &opt, &pause)?;
+            if !opt.loop_playback || pause.is_stopped() {
+                break;
+            }
+            pass += 1;
+            status!(opt, "- restarting playback (pass {})", pass);
+        }
         return Ok(());
     }
 
-    // Read each FIT file and output it
-    for file in opt.files {
-        // open file and parse data
-        println!("Reading from: {:?}", file);
-        println!("Writing   to: {:?}", output_loc);
+    // output each FIT file, the files left are skipped once playback was stopped
+    wait_until(start, &opt, &pause);
+    for activity in read.into_iter().take_while(|_| !pause.is_stopped()) {
+        status!(opt, "Reading from: {:?}", activity.name);
+        status!(opt, "Writing   to: {}", output_loc);
+
+        let rider = roster.rider(&activity.stem);
+        let data = select_sessions(FitDataMap::from_records(activity.records), &opt)?;
+        mode.handle(data, &opt, &rider, &pause)?;
+    }
+
+    mode.finish()?;
+    all_failed(failed, &opt)
+}
+
+/// Activities of all input files in the order of the files and the number of files which
+/// couldn't be read. The files are parsed in parallel, the errors are printed in order.
+fn read_all(decode_opts: &HashSet<DecodeOption>, opt: &Cli) -> (Vec<input::Activity>, usize) {
+    let read: Vec<Result<Vec<input::Activity>, String>> = opt
+        .files
+        .par_iter()
+        .map(|file| {
+            input::read_path(file, decode_opts, opt.recover, Some(input::PLAYBACK_MESSAGES))
+                .map_err(|err| err.to_string())
+        })
+        .collect();
 
-        let mut fp = File::open(&file)?;
-        let data = from_reader_with_options(&mut fp, &decode_opts)?;
-        write_json_file_focus(output_loc, data, opt.delay)?;
-        println!("");
+    let mut activities = Vec::new();
+    let mut failed = 0;
+    for (file, result) in opt.files.iter().zip(read) {
+        match result {
+            Ok(read) => activities.extend(read),
+            Err(err) => {
+                eprintln!("Error: skipping {:?}: {}", file, err);
+                failed += 1;
+            }
+        }
     }
+    (activities, failed)
+}
 
+/// Fail if none of the input files could be read
+fn all_failed(failed: usize, opt: &Cli) -> Result<(), Box<dyn Error>> {
+    if failed == opt.files.len() {
+        return Err("none of the files could be read".into());
+    }
     Ok(())
 }
 
 fn main() {
-    std::process::exit(match run() {
+    let result = run();
+    keyboard::restore();
+    std::process::exit(match result {
         Ok(_) => 0,
         Err(err) => {
             eprintln!("{}", err);
             1
         }
     });
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_plays_the_sessions_of_all_files() {
+        let opt = Cli::from_iter(["tpvfitplay", "--merge", "-q", "tests/ride_1.fit", "tests/ride_2.fit"]);
+        let (read, failed) = read_all(&HashSet::new(), &opt);
+        assert_eq!(failed, 0);
+        let data = merge_activities(read, &opt).unwrap();
+        // both rides, 4498 and 7687 frames on their own
+        assert_eq!(frames(&data, &opt.convert, &Rider::default()).count(), 12185);
+    }
+
+    #[test]
+    fn merged_golden() {
+        let opt = Cli::from_iter(["tpvfitplay", "--merge", "-q", "tests/ride_1.fit", "tests/ride_2.fit"]);
+        let (read, _) = read_all(&HashSet::new(), &opt);
+        let data = merge_activities(read, &opt).unwrap();
+        let golden: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/ride_1_2_merged_last.json")).unwrap();
+        let last = frames(&data, &opt.convert, &Rider::default()).last().unwrap();
+        assert_eq!(serde_json::to_value(last).unwrap(), golden);
+    }
+}
@@ -0,0 +1,106 @@
+//! Field mapping read from the '--map' TOML file, retargets FIT fields to focus fields
+//!
+//! Each table is named after the focus field it fills and names the FIT field to read from,
+//! the value is scaled and offset before it is converted into the focus unit:
+//!
+//! ```toml
+//! [power]
+//! field = "Trainer Power"
+//!
+//! [height]
+//! field = "altitude"
+//! scale = 1.0
+//! offset = -100.0
+//! ```
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::{clamp_i32, clamp_u32, value_f64, FitDataMap, Sample};
+
+/// Focus fields which can be mapped, the value is expected in the unit of the matching
+/// built-in FIT field
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum Target {
+    /// watts
+    Power,
+    /// percent left
+    PowerBalance,
+    /// bpm
+    Heartrate,
+    /// rpm
+    Cadence,
+    /// meters
+    Distance,
+    /// m/s
+    Speed,
+    /// percent
+    Slope,
+    /// meters
+    Height,
+    /// degrees Celsius
+    Temperature,
+    /// percent
+    Draft,
+}
+
+/// Source of one focus field
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mapping {
+    /// FIT field name, developer fields by their name too
+    field: String,
+    #[serde(default = "one")]
+    scale: f64,
+    #[serde(default)]
+    offset: f64,
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+/// All mappings of the config, empty for the built-in mapping only
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct FieldMap {
+    mappings: BTreeMap<Target, Mapping>,
+}
+
+impl FieldMap {
+    pub fn load(filename: &Path) -> Result<FieldMap, Box<dyn Error>> {
+        let content = fs::read_to_string(filename)?;
+        toml::from_str(&content).map_err(|err| format!("invalid map file {:?}: {}", filename, err).into())
+    }
+
+    /// Overwrite the mapped fields of a sample decoded by the built-in mapping, fields the
+    /// record doesn't carry are cleared
+    pub fn apply(&self, fdm: &FitDataMap, sample: &mut Sample) {
+        for (target, mapping) in &self.mappings {
+            let value = fdm
+                .fields
+                .get(&mapping.field)
+                .and_then(value_f64)
+                .map(|v| v * mapping.scale + mapping.offset)
+                .filter(|v| v.is_finite());
+            let int = value.map(|v| clamp_u32(v.round()));
+
+            match target {
+                Target::Power => sample.power = int,
+                Target::PowerBalance => sample.balance = int,
+                Target::Heartrate => sample.heartrate = int,
+                Target::Cadence => sample.cadence = int,
+                Target::Distance => sample.distance = value,
+                Target::Speed => sample.speed = value,
+                Target::Slope => sample.grade = value,
+                Target::Height => sample.altitude = value,
+                Target::Temperature => sample.temperature = value.map(|v| clamp_i32(v.round())),
+                Target::Draft => sample.draft = value,
+            }
+        }
+    }
+}
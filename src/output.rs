@@ -0,0 +1,214 @@
+//! Destinations receiving the serialized focus frames during playback
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, SeekFrom};
+use std::net::UdpSocket;
+use std::path::{Path, PathBuf};
+
+/// Receives every serialized focus frame
+pub trait Sink {
+    /// Human readable description of the destination
+    fn describe(&self) -> String;
+
+    /// Publish the next frame
+    fn send(&mut self, frame: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Publish the next frame with the names of its riders in order, for sinks which need
+    /// them while '--fields' may have dropped the name from the frame
+    fn send_named(&mut self, frame: &str, _names: &[&str]) -> Result<(), Box<dyn Error>> {
+        self.send(frame)
+    }
+}
+
+/// Overwrites the output file with each new frame.
+///
+/// Each frame is written to a temporary file next to the output first and then renamed onto
+/// it, so readers polling the file never see a partially written frame. Without the rename
+/// the output is opened once and rewritten in place.
+pub struct FileSink {
+    filename: PathBuf,
+    /// temporary file renamed onto the output, None to rewrite the output in place
+    temp: Option<PathBuf>,
+    fp: Option<File>,
+}
+
+impl FileSink {
+    pub fn new(filename: &Path, atomic: bool) -> FileSink {
+        // same directory as the output, renames across file systems would fail
+        let name = filename
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        FileSink {
+            filename: filename.to_path_buf(),
+            temp: atomic.then(|| filename.with_file_name(format!(".{}.tmp", name))),
+            fp: None,
+        }
+    }
+}
+
+impl Sink for FileSink {
+    fn describe(&self) -> String {
+        format!("{:?}", self.filename)
+    }
+
+    fn send(&mut self, frame: &str) -> Result<(), Box<dyn Error>> {
+        if let Some(temp) = &self.temp {
+            let mut fp = File::create(temp)?;
+            fp.write_all(frame.as_bytes())?;
+            fs::rename(temp, &self.filename)?;
+            return Ok(());
+        }
+
+        let fp = match &mut self.fp {
+            Some(fp) => fp,
+            None => {
+                let fp = OpenOptions::new().write(true).create(true).truncate(true).open(&self.filename)?;
+                self.fp.insert(fp)
+            }
+        };
+        fp.seek(SeekFrom::Start(0))?;
+        fp.write_all(frame.as_bytes())?;
+        fp.set_len(frame.len() as u64)?;
+        Ok(())
+    }
+}
+
+/// Writes each frame as a line to stdout, for '-o -'
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn describe(&self) -> String {
+        String::from("stdout")
+    }
+
+    fn send(&mut self, frame: &str) -> Result<(), Box<dyn Error>> {
+        let mut out = io::stdout().lock();
+        // a '--pretty' frame spans lines, the next one starts on a line of its own
+        writeln!(out, "{}", frame)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Sends each frame as a single UDP datagram.
+///
+/// A single rider frame is about 500 bytes and fits into a typical 1500 byte MTU, larger
+/// frames (e.g. many riders) get fragmented by the IP layer.
+pub struct UdpSink {
+    addr: String,
+    socket: UdpSocket,
+}
+
+impl UdpSink {
+    pub fn new(addr: &str) -> Result<UdpSink, Box<dyn Error>> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_broadcast(true)?;
+        socket
+            .connect(addr)
+            .map_err(|err| format!("unable to send UDP to {}: {}", addr, err))?;
+        Ok(UdpSink {
+            addr: addr.to_string(),
+            socket,
+        })
+    }
+}
+
+impl Sink for UdpSink {
+    fn describe(&self) -> String {
+        format!("udp://{}", self.addr)
+    }
+
+    fn send(&mut self, frame: &str) -> Result<(), Box<dyn Error>> {
+        match self.socket.send(frame.as_bytes()) {
+            // nobody listening (yet), the frame is just lost like any datagram
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+            Err(err) => Err(err.into()),
+            Ok(_) => Ok(()),
+        }
+    }
+}
+
+/// Focus fields describing the environment rather than the rider, 'height' is the altitude
+pub const ENV_FIELDS: [&str; 5] = ["slope", "windSpeed", "windAngle", "temperature", "height"];
+
+/// Moves the environment out of the frames into a file of its own for '--env-output', the
+/// other sinks get the rider metrics only.
+///
+/// The environment file is written right before the frame, both by renaming a temporary file.
+pub struct EnvSplit {
+    /// None if the environment goes to the file of another split
+    env: Option<FileSink>,
+    riders: Vec<Box<dyn Sink>>,
+    pretty: bool,
+}
+
+impl EnvSplit {
+    pub fn new(filename: &Path, riders: Vec<Box<dyn Sink>>, pretty: bool) -> EnvSplit {
+        EnvSplit {
+            env: Some(FileSink::new(filename, true)),
+            riders,
+            pretty,
+        }
+    }
+
+    /// Only remove the environment from the frames, for the per rider outputs of
+    /// '--output-dir' while the frame of all riders writes the environment file
+    pub fn strip(riders: Vec<Box<dyn Sink>>, pretty: bool) -> EnvSplit {
+        EnvSplit {
+            env: None,
+            riders,
+            pretty,
+        }
+    }
+
+    fn to_json(&self, frame: &[serde_json::Value]) -> serde_json::Result<String> {
+        if self.pretty {
+            serde_json::to_string_pretty(frame)
+        } else {
+            serde_json::to_string(frame)
+        }
+    }
+}
+
+impl Sink for EnvSplit {
+    fn describe(&self) -> String {
+        let mut outputs: Vec<String> = self.riders.iter().map(|o| o.describe()).collect();
+        if let Some(env) = &self.env {
+            outputs.push(format!("{} (environment)", env.describe()));
+        }
+        outputs.join(", ")
+    }
+
+    fn send(&mut self, frame: &str) -> Result<(), Box<dyn Error>> {
+        self.send_named(frame, &[])
+    }
+
+    fn send_named(&mut self, frame: &str, names: &[&str]) -> Result<(), Box<dyn Error>> {
+        let mut riders: Vec<serde_json::Value> = serde_json::from_str(frame)?;
+        let env: Vec<serde_json::Value> = riders
+            .iter_mut()
+            .map(|focus| {
+                let mut env = serde_json::Map::new();
+                if let Some(focus) = focus.as_object_mut() {
+                    for name in ENV_FIELDS {
+                        if let Some(value) = focus.shift_remove(name) {
+                            env.insert(name.to_string(), value);
+                        }
+                    }
+                }
+                serde_json::Value::Object(env)
+            })
+            .collect();
+
+        let env = self.to_json(&env)?;
+        if let Some(sink) = &mut self.env {
+            sink.send(&env)?;
+        }
+        let riders = self.to_json(&riders)?;
+        for output in self.riders.iter_mut() {
+            output.send_named(&riders, names)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! The 'powercurve' subcommand, prints the best average power over a set of durations
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{info, input};
+use tpvfitplay::{frames, ConvertOptions, FitDataMap, Focus, Rider};
+
+/// Durations of the best efforts in seconds, e.g. '5s,30s,1m,5m,20m,60m'
+#[derive(Clone, Debug)]
+pub struct Durations(Vec<u32>);
+
+impl FromStr for Durations {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Durations, String> {
+        let mut durations = s
+            .split(',')
+            .map(|d| duration(d.trim()))
+            .collect::<Result<Vec<u32>, String>>()?;
+        durations.sort_unstable();
+        durations.dedup();
+        Ok(Durations(durations))
+    }
+}
+
+/// Seconds of a single duration, plain or with 'h', 'm' and 's' parts like '1h30m'
+fn duration(s: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid duration '{}', expected e.g. 30s, 5m or 1h30m", s);
+    let mut seconds: u32 = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        let scale = match c {
+            '0'..='9' => {
+                number.push(c);
+                continue;
+            }
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let part = number.parse::<u32>().map_err(|_| invalid())?;
+        seconds = part.checked_mul(scale).and_then(|part| seconds.checked_add(part)).ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        seconds = number.parse::<u32>().ok().and_then(|part| seconds.checked_add(part)).ok_or_else(invalid)?;
+    }
+    if seconds == 0 {
+        return Err(invalid());
+    }
+    Ok(seconds)
+}
+
+/// Convert all records like the playback does and print the best average power of each duration
+pub fn run(path: &Path, durations: &Durations, opt: ConvertOptions) -> Result<(), Box<dyn Error>> {
+    for activity in input::read_path(path, &HashSet::new(), false, None)? {
+        let data = FitDataMap::from_records(activity.records);
+        println!("File      : {}", activity.name);
+
+        let (first, power) = power_by_second(frames(&data, &opt, &Rider::default()));
+        if power.is_empty() {
+            println!("No records\n");
+            continue;
+        }
+
+        println!("Power curve");
+        for &seconds in &durations.0 {
+            let label = clock(seconds);
+            match best_effort(&power, seconds as usize) {
+                Some((watts, start)) => println!(
+                    "  {:>8} : {:>4} W (from {})",
+                    label,
+                    watts.round() as u32,
+                    info::duration(Some(first + start as f64))
+                ),
+                None => println!("  {:>8} :   -- (ride is shorter)", label),
+            }
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Elapsed seconds of the first frame and the mean power of every second from there on,
+/// seconds without a frame (recording gaps) count as 0 W
+fn power_by_second(frames: impl Iterator<Item = Focus>) -> (f64, Vec<f64>) {
+    let mut sums: Vec<(f64, u32)> = Vec::new();
+    let mut first = None;
+    for focus in frames {
+        let second = (focus.elapsed - *first.get_or_insert(focus.elapsed)).max(0.0) as usize;
+        if sums.len() <= second {
+            sums.resize(second + 1, (0.0, 0));
+        }
+        sums[second].0 += focus.power as f64;
+        sums[second].1 += 1;
+    }
+    let power = sums
+        .into_iter()
+        .map(|(sum, count)| if count > 0 { sum / count as f64 } else { 0.0 })
+        .collect();
+    (first.unwrap_or(0.0), power)
+}
+
+/// Highest mean power over `seconds` consecutive seconds and the second it starts at, None
+/// if the ride is shorter. A running sum slides over the ride once.
+fn best_effort(power: &[f64], seconds: usize) -> Option<(f64, usize)> {
+    if seconds == 0 || power.len() < seconds {
+        return None;
+    }
+    let mut sum: f64 = power[..seconds].iter().sum();
+    let mut best = (sum, 0);
+    for end in seconds..power.len() {
+        sum += power[end] - power[end - seconds];
+        if sum > best.0 {
+            best = (sum, end + 1 - seconds);
+        }
+    }
+    Some((best.0 / seconds as f64, best.1))
+}
+
+/// Duration label like '30s', '5m' or '1h30m'
+fn clock(seconds: u32) -> String {
+    let (h, m, s) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    [(h, "h"), (m, "m"), (s, "s")]
+        .iter()
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect()
+}
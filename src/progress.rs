@@ -0,0 +1,124 @@
+//! Progress of the playback, a single line updated in place on a terminal
+use std::io::{self, IsTerminal, Write};
+use std::time::Duration;
+
+use crate::{export, frame_delay, Cli};
+
+const WIDTH: usize = 30;
+/// Percent of the playback between the lines written to a log instead of a terminal
+const LOG_STEP: u64 = 10;
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+/// Progress line of one playback pass, written to stderr if stdout carries the output.
+///
+/// If the stream is not a terminal the line is not updated in place, a plain line is written
+/// every LOG_STEP percent instead (every minute of recorded time without a total).
+pub struct Progress {
+    start: f64,
+    /// recorded seconds at the end of the playback, None shows a spinner instead of a bar
+    total: Option<f64>,
+    /// wall-clock time per recorded second, None if playback doesn't wait
+    delay: Option<Duration>,
+    stderr: bool,
+    quiet: bool,
+    terminal: bool,
+    ticks: usize,
+    /// last step written to a log
+    logged: Option<u64>,
+}
+
+impl Progress {
+    pub fn new(start: f64, total: Option<f64>, opt: &Cli) -> Progress {
+        let stderr = export::is_stdout(&opt.output);
+        let terminal = if stderr {
+            io::stderr().is_terminal()
+        } else {
+            io::stdout().is_terminal()
+        };
+        Progress {
+            start,
+            total: total.filter(|total| *total > start),
+            delay: frame_delay(opt),
+            stderr,
+            quiet: opt.quiet,
+            terminal,
+            ticks: 0,
+            logged: None,
+        }
+    }
+
+    /// Redraw the line at `elapsed` recorded seconds
+    pub fn update(&mut self, elapsed: f64) {
+        if self.quiet {
+            return;
+        }
+        if !self.terminal {
+            let step = match self.total {
+                Some(total) => ((elapsed - self.start) / (total - self.start) * 100.0) as u64 / LOG_STEP,
+                None => elapsed as u64 / 60,
+            };
+            if self.logged == Some(step) {
+                return;
+            }
+            self.logged = Some(step);
+        }
+
+        let line = match self.total {
+            Some(total) => {
+                let elapsed = elapsed.clamp(self.start, total);
+                let done = (elapsed - self.start) / (total - self.start);
+                let filled = (done * WIDTH as f64).round() as usize;
+                let eta = match self.delay {
+                    Some(delay) => clock(delay.mul_f64(total - elapsed).as_secs_f64()),
+                    None => String::from("--:--"),
+                };
+                format!(
+                    "[{}{}] {} / {} {:3.0}% ETA {}",
+                    "#".repeat(filled),
+                    "-".repeat(WIDTH - filled),
+                    clock(elapsed),
+                    clock(total),
+                    done * 100.0,
+                    eta
+                )
+            }
+            None => {
+                self.ticks += 1;
+                format!("{} {}", SPINNER[self.ticks % SPINNER.len()], clock(elapsed))
+            }
+        };
+        if self.terminal {
+            self.print(&format!("\r\x1b[K{}", line));
+        } else {
+            self.print(&format!("{}\n", line));
+        }
+    }
+
+    /// Leave the last state on screen and move to the next line
+    pub fn finish(&self) {
+        if self.terminal && !self.quiet {
+            self.print("\n");
+        }
+    }
+
+    fn print(&self, text: &str) {
+        // progress is informational only, a closed terminal must not stop the playback
+        if self.stderr {
+            let mut err = io::stderr();
+            let _ = err.write_all(text.as_bytes()).and_then(|_| err.flush());
+        } else {
+            let mut out = io::stdout();
+            let _ = out.write_all(text.as_bytes()).and_then(|_| out.flush());
+        }
+    }
+}
+
+/// Seconds as H:MM:SS, or M:SS below an hour
+fn clock(seconds: f64) -> String {
+    let seconds = seconds as u64;
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
@@ -0,0 +1,157 @@
+//! Tiny HTTP server handing out the latest focus frame on 'GET /focus.json', 'GET /events'
+//! streams the frames as Server-Sent Events.
+//!
+//! The playback is controlled by 'POST /pause', 'POST /resume' and 'POST /seek?t=<seconds>',
+//! 'GET /state' tells where it is.
+use crate::keyboard::Pause;
+use crate::output::Sink;
+use serde_json::json;
+use std::error::Error;
+use std::io::Write;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Channels to the clients streaming '/events'
+type Listeners = Arc<Mutex<Vec<Sender<String>>>>;
+
+/// Publishes the frames to the HTTP server
+pub struct HttpSink {
+    addr: String,
+    frame: Arc<Mutex<String>>,
+    listeners: Listeners,
+}
+
+impl Sink for HttpSink {
+    fn describe(&self) -> String {
+        format!("http://{}/focus.json", self.addr)
+    }
+
+    fn send(&mut self, frame: &str) -> Result<(), Box<dyn Error>> {
+        let mut current = self.frame.lock().map_err(|_| "HTTP frame lock poisoned")?;
+        current.clear();
+        current.push_str(frame);
+        if let Ok(mut listeners) = self.listeners.lock() {
+            // clients which went away are dropped
+            listeners.retain(|listener| listener.send(frame.to_string()).is_ok());
+        }
+        Ok(())
+    }
+}
+
+/// Start the HTTP server on `addr` (e.g. '127.0.0.1:8080') in a background thread, the
+/// control routes act on `pause`
+pub fn serve(addr: &str, pause: &Pause) -> Result<HttpSink, Box<dyn Error>> {
+    let server = Server::http(addr).map_err(|err| format!("unable to serve on {}: {}", addr, err))?;
+    let frame = Arc::new(Mutex::new(String::from("[]")));
+
+    let listeners = Listeners::default();
+
+    let shared = Arc::clone(&frame);
+    let subscribed = Arc::clone(&listeners);
+    let pause = pause.clone();
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+            if request.method() == &Method::Get && path == "/events" {
+                stream_events(request, &shared, &subscribed);
+                continue;
+            }
+            let response = match (request.method(), path) {
+                (Method::Get, "/focus.json") => {
+                    let body = shared.lock().map(|f| f.clone()).unwrap_or_default();
+                    json_response(body)
+                }
+                (Method::Get, "/state") => json_response(state(&pause)),
+                (Method::Post, "/pause") => {
+                    pause.set_paused(true);
+                    json_response(state(&pause))
+                }
+                (Method::Post, "/resume") => {
+                    pause.set_paused(false);
+                    json_response(state(&pause))
+                }
+                (Method::Post, "/seek") => match seek_time(query) {
+                    Err(err) => Response::from_string(err).with_status_code(400),
+                    Ok(time) if pause.seek(time) => json_response(state(&pause)),
+                    Ok(_) => Response::from_string("playback can't seek right now").with_status_code(409),
+                },
+                _ => Response::from_string("not found").with_status_code(404),
+            };
+            if let Err(err) = request.respond(response) {
+                eprintln!("Warning: HTTP response failed: {}", err);
+            }
+        }
+    });
+
+    Ok(HttpSink {
+        addr: addr.to_string(),
+        frame,
+        listeners,
+    })
+}
+
+/// Stream the frames to the client in a thread of its own, starting with the current frame
+fn stream_events(request: Request, frame: &Mutex<String>, listeners: &Listeners) {
+    let (sender, frames) = mpsc::channel::<String>();
+    // subscribed while holding the frame, so no frame is missed in between
+    if let (Ok(current), Ok(mut listeners)) = (frame.lock(), listeners.lock()) {
+        let _ = sender.send(current.clone());
+        listeners.push(sender);
+    }
+
+    thread::spawn(move || {
+        // written by hand, the chunked responses of tiny_http are buffered
+        let mut out = request.into_writer();
+        let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+                    Connection: close\r\nAccess-Control-Allow-Origin: *\r\n\r\n";
+        if out.write_all(head.as_bytes()).and_then(|_| out.flush()).is_err() {
+            return;
+        }
+        for frame in frames {
+            if out.write_all(event(&frame).as_bytes()).and_then(|_| out.flush()).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Frame as a single event, every line of a '--pretty' frame gets its own 'data' field
+fn event(frame: &str) -> String {
+    let mut event: String = frame.lines().map(|line| format!("data: {}\n", line)).collect();
+    event.push('\n');
+    event
+}
+
+/// Playback state as returned by the control routes
+fn state(pause: &Pause) -> String {
+    let state = if pause.is_stopped() {
+        "stopped"
+    } else if pause.is_paused() {
+        "paused"
+    } else {
+        "playing"
+    };
+    json!({"state": state, "time": pause.position(), "seekable": pause.is_seekable()}).to_string()
+}
+
+/// Recorded time of the 't' query parameter, as seconds, MM:SS or HH:MM:SS
+fn seek_time(query: &str) -> Result<u32, String> {
+    let value = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("t="))
+        .ok_or("missing query parameter 't'")?;
+    // clients may send the colons of MM:SS encoded
+    tpvfitplay::parse_offset(&value.replace("%3A", ":").replace("%3a", ":"))
+}
+
+fn json_response(body: String) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(body)
+        .with_header(header("Content-Type", "application/json"))
+        .with_header(header("Access-Control-Allow-Origin", "*"))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid static header")
+}
@@ -0,0 +1,75 @@
+//! The 'stats' subcommand, prints the totals and averages of a whole ride without playback
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+
+use crate::{info, input, Units};
+use tpvfitplay::{efficiency_factor, frames, samples, ConvertOptions, FitDataMap, Rider, Sample};
+
+/// Convert all records like the playback does and print the metrics of the last frame
+pub fn run(path: &Path, units: Units, opt: ConvertOptions) -> Result<(), Box<dyn Error>> {
+    for activity in input::read_path(path, &HashSet::new(), false, None)? {
+        let data = FitDataMap::from_records(activity.records);
+        println!("File      : {}", activity.name);
+
+        let focus = match frames(&data, &opt, &Rider::default()).last() {
+            Some(focus) => focus,
+            None => {
+                println!("No records\n");
+                continue;
+            }
+        };
+
+        println!("Elapsed   : {}", info::duration(Some(focus.time as f64)));
+        println!("Moving    : {}", info::duration(Some(focus.movingTime as f64)));
+        // back from the TPV speed scale into m/s
+        let speed = |scaled: u32| scaled as f64 / opt.speed_scale as f64 / 3.6;
+        println!(
+            "Speed     : avg {:.1} {unit}, max {:.1} {unit}",
+            units.speed(speed(focus.avgSpeed)),
+            units.speed(speed(focus.maxSpeed)),
+            unit = units.speed_label()
+        );
+        println!("Distance  : {:.2} {}", units.distance(focus.distance as f64), units.distance_unit());
+        println!(
+            "Ascent    : {:.0} {}",
+            units.height(focus.totalAscent as f64),
+            units.height_unit()
+        );
+        println!(
+            "Power     : avg {} W, NP {} W, max {} W",
+            focus.avgPower, focus.nrmPower, focus.maxPower
+        );
+        println!("Heart rate: avg {} bpm, max {} bpm", focus.avgHeartrate, focus.maxHeartrate);
+        println!("Cadence   : avg {} rpm, max {} rpm", focus.avgCadence, focus.maxCadence);
+        println!("Work      : {} kJ", focus.workKj);
+        match (focus.efficiencyFactor, decoupling(&data, &opt)) {
+            (0, _) => println!("EF        : -- (no power or heart rate)"),
+            (ef, Some(decoupling)) => {
+                println!("EF        : {:.2}, decoupling {:.1}%", ef as f64 / 100.0, decoupling)
+            }
+            (ef, None) => println!("EF        : {:.2}, decoupling --", ef as f64 / 100.0),
+        }
+        if focus.intensityFactor > 0 {
+            println!("TSS       : {} (IF {:.2})", focus.tss, focus.intensityFactor as f64 / 100.0);
+        } else {
+            println!("TSS       : -- (no FTP given or recorded)");
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Aerobic decoupling (Pa:HR) in percent, how much the efficiency factor of the recorded
+/// second half of the ride dropped against the first half, None if a half lacks power or
+/// heart rate
+fn decoupling(data: &[FitDataMap], opt: &ConvertOptions) -> Option<f64> {
+    let samples: Vec<Sample> = samples(data, opt).collect();
+    let start = samples.iter().find_map(|sample| sample.timestamp)?;
+    let end = samples.iter().rev().find_map(|sample| sample.timestamp)?;
+    let middle = (start + end) / 2.0;
+    let half = samples.partition_point(|sample| sample.timestamp.is_none_or(|t| t < middle));
+    let (first, second) = samples.split_at(half);
+    let (first, second) = (efficiency_factor(first)?, efficiency_factor(second)?);
+    Some((first - second) / first * 100.0)
+}
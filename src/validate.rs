@@ -0,0 +1,67 @@
+//! The 'validate' subcommand, checks that a FIT file decodes without playing it back
+use fitparser::de::DecodeOption;
+use fitparser::ErrorKind;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::path::Path;
+
+use crate::input;
+
+/// Decode the file with CRC checks, print the CRC status and the number of messages per
+/// kind, fails if the file doesn't decode or the CRC doesn't match
+pub fn run(path: &Path) -> Result<(), Box<dyn Error>> {
+    println!("Validating: {:?}", path);
+
+    let mut crc_ok = true;
+    let activities = match input::read_path(path, &HashSet::new(), false, None) {
+        Ok(activities) => activities,
+        Err(err) if is_crc_error(err.as_ref()) => {
+            println!("CRC       : {}", err);
+            crc_ok = false;
+            // decode again to see if anything else is wrong with the file
+            let decode_opts = HashSet::from([
+                DecodeOption::SkipHeaderCrcValidation,
+                DecodeOption::SkipDataCrcValidation,
+            ]);
+            input::read_path(path, &decode_opts, false, None)
+                .map_err(|err| decode_error(path, &decode_opts, err))?
+        }
+        Err(err) => return Err(decode_error(path, &HashSet::new(), err).into()),
+    };
+    if crc_ok {
+        println!("CRC       : ok");
+    }
+
+    for activity in &activities {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for record in &activity.records {
+            *counts.entry(record.kind().to_string()).or_default() += 1;
+        }
+
+        println!("Messages  : {} in {}", activity.records.len(), activity.name);
+        for (kind, count) in &counts {
+            println!("  {:<24} {:>6}", kind, count);
+        }
+    }
+
+    if !crc_ok {
+        return Err("CRC check failed, use --no-crc-check to play back the file anyway".into());
+    }
+    println!("File is playable");
+    Ok(())
+}
+
+/// Message of a failed decode with the byte offset the error was found at if the file can be
+/// decoded message by message
+fn decode_error(path: &Path, decode_opts: &HashSet<DecodeOption>, err: Box<dyn Error>) -> String {
+    match input::error_offset(path, decode_opts) {
+        Ok(Some((decoded, size))) => format!("decoding failed at byte {} of {}, {}", decoded, size, err),
+        _ => format!("decoding failed, {}", err),
+    }
+}
+
+/// True if decoding only failed because of a CRC mismatch
+fn is_crc_error(err: &(dyn Error + 'static)) -> bool {
+    err.downcast_ref::<fitparser::Error>()
+        .is_some_and(|err| matches!(**err, ErrorKind::InvalidCrc(..)))
+}
@@ -0,0 +1,81 @@
+//! WebSocket server pushing every focus frame to all connected clients
+use crate::output::Sink;
+use std::error::Error;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tungstenite::{Message, WebSocket};
+
+/// Connected clients plus the latest frame for clients joining late
+#[derive(Default)]
+struct Clients {
+    latest: Option<String>,
+    sockets: Vec<WebSocket<TcpStream>>,
+}
+
+/// Hands the frames over to the WebSocket broadcaster
+pub struct WsSink {
+    addr: String,
+    frames: Sender<String>,
+}
+
+impl Sink for WsSink {
+    fn describe(&self) -> String {
+        format!("ws://{}", self.addr)
+    }
+
+    fn send(&mut self, frame: &str) -> Result<(), Box<dyn Error>> {
+        self.frames
+            .send(frame.to_string())
+            .map_err(|_| "WebSocket broadcaster stopped")?;
+        Ok(())
+    }
+}
+
+/// Start accepting WebSocket clients on `addr` (e.g. '127.0.0.1:8081') in the background
+pub fn serve(addr: &str) -> Result<WsSink, Box<dyn Error>> {
+    let listener = TcpListener::bind(addr)
+        .map_err(|err| format!("unable to open WebSocket on {}: {}", addr, err))?;
+    let clients = Arc::new(Mutex::new(Clients::default()));
+
+    let accepting = Arc::clone(&clients);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut socket = match tungstenite::accept(stream) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    eprintln!("Warning: WebSocket handshake failed: {}", err);
+                    continue;
+                }
+            };
+            if let Ok(mut clients) = accepting.lock() {
+                // late joiners get the current frame right away
+                if let Some(latest) = &clients.latest {
+                    if socket.send(Message::text(latest.clone())).is_err() {
+                        continue;
+                    }
+                }
+                clients.sockets.push(socket);
+            }
+        }
+    });
+
+    let (frames, received) = mpsc::channel::<String>();
+    thread::spawn(move || {
+        for frame in received {
+            if let Ok(mut clients) = clients.lock() {
+                // clients which can't be written to anymore are dropped
+                clients
+                    .sockets
+                    .retain_mut(|socket| socket.send(Message::text(frame.clone())).is_ok());
+                clients.latest = Some(frame);
+            }
+        }
+    });
+
+    Ok(WsSink {
+        addr: addr.to_string(),
+        frames,
+    })
+}
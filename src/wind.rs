@@ -0,0 +1,50 @@
+//! Simulated wind read from the '--wind-from' JSON file
+//!
+//! The file is an array of wind vectors by elapsed seconds, each holds until the next one:
+//!
+//! ```json
+//! [{"time": 0, "speed": 12.0, "angle": 90}, {"time": 600, "speed": 20.5, "angle": 135}]
+//! ```
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// Wind from `time` on
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WindVector {
+    /// elapsed seconds
+    time: f64,
+    /// km/h
+    speed: f64,
+    /// degrees
+    angle: f64,
+}
+
+/// All wind vectors ordered by time, empty without '--wind-from'
+#[derive(Clone, Debug, Default)]
+pub struct Wind {
+    vectors: Vec<WindVector>,
+}
+
+impl Wind {
+    pub fn load(filename: &Path) -> Result<Wind, Box<dyn Error>> {
+        let fp = File::open(filename)?;
+        let mut vectors: Vec<WindVector> = serde_json::from_reader(io::BufReader::new(fp))
+            .map_err(|err| format!("invalid wind file {:?}: {}", filename, err))?;
+        vectors.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Ok(Wind { vectors })
+    }
+
+    /// Speed in km/h and angle in degrees at `elapsed` seconds, None before the first vector
+    pub fn at(&self, elapsed: f64) -> Option<(f64, f64)> {
+        self.vectors
+            .iter()
+            .take_while(|v| v.time <= elapsed)
+            .last()
+            .map(|v| (v.speed, v.angle))
+    }
+}